@@ -0,0 +1,386 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::{
+    parse_macro_input, spanned::Spanned, Data, DeriveInput, Error, Expr, Fields, GenericArgument, Ident, LitInt,
+    PathArguments, Result, Type,
+};
+
+// `#[derive(HackletMessage)]` - generates `Message::encode`, `Message::read`,
+// `as_bytes` and getters for the framing fields of a message struct:
+//
+//     #[derive(Debug, HackletMessage)]
+//     #[hacklet(command = 0x40A2, payload_length = 3)]
+//     pub struct UpdateTimeResponse
+//     {
+//         header: u8,
+//         command: u16,
+//         payload_length: u8,
+//         pub network_id: u16,
+//         pub data: u8,
+//         checksum: u8,
+//     }
+//
+// The struct starts with `header`, `command` and `payload_length` and ends
+// with `checksum`, everything in between is the payload in wire order. The
+// framing fields stay private so only `new` and `read` set them. The
+// payload fields have to add up to `payload_length`, checked when the derive
+// runs. Only a message with a `count` field may leave `payload_length` out and
+// accept any length. Payload fields are big-endian unsigned integers unless
+// marked:
+//
+//     #[hacklet(le)]                       little-endian
+//     #[hacklet(le, bytes = 3)]            only the low three bytes are sent
+//     #[hacklet(len = 12)]                 `Vec<u8>` of a fixed length
+//     #[hacklet(le, count = sample_count)] `Vec<u16>`, length from an earlier field
+//     #[hacklet(expect = LOCK_DATA)]       reading fails unless the field equals it
+//
+// `#[hacklet(no_payload)]` next to `payload_length` is for a frame whose length
+// byte claims a payload that is never sent, like the boot confirm request.
+//
+// Reads fail with a nom error, not `Incomplete`, when the command, length or
+// an `expect` field does not match, so messages can be tried in turn with `alt`.
+// A message with a `count` field is read from exactly `payload_length` bytes,
+// a count that does not fill them is an error too.
+
+#[proc_macro_derive(HackletMessage, attributes(hacklet))]
+pub fn derive_hacklet_message(input: TokenStream) -> TokenStream
+{
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+const HEADER_FIELDS: [&str; 3] = ["header", "command", "payload_length"];
+
+struct MessageAttributes
+{
+    command: LitInt,
+    payload_length: Option<LitInt>,
+    no_payload: bool,
+}
+
+enum Kind
+{
+    // Unsigned integer of `bits` bits, `bytes` of which are on the wire
+    Integer { bits: usize, bytes: usize },
+    Bytes { len: LitInt },
+    Counted { bits: usize, count: Ident },
+}
+
+struct Field
+{
+    name: Ident,
+    kind: Kind,
+    little_endian: bool,
+    expect: Option<Expr>,
+}
+
+fn expand(input: &DeriveInput) -> Result<TokenStream2>
+{
+    let name = &input.ident;
+    let attributes = message_attributes(input)?;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new(input.span(), "HackletMessage needs named fields")),
+        },
+        _ => return Err(Error::new(input.span(), "HackletMessage can only be derived for structs")),
+    };
+
+    let names: Vec<String> = fields.iter().map(|field| field.ident.as_ref().unwrap().to_string()).collect();
+    if names.len() < 4 || names[..3] != HEADER_FIELDS || names[names.len() - 1] != "checksum" {
+        return Err(Error::new(
+            input.span(),
+            "message fields must start with header, command and payload_length and end with checksum",
+        ));
+    }
+    let payload = fields
+        .iter()
+        .skip(3)
+        .take(fields.len() - 4)
+        .map(payload_field)
+        .collect::<Result<Vec<_>>>()?;
+
+    check_payload_length(input, &attributes, &payload)?;
+
+    let encode = payload.iter().map(encode_field);
+    // Without a fixed layout the length byte is the only thing that says where
+    // the frame ends, so the fields have to add up to it
+    let counted = payload.iter().any(|field| matches!(field.kind, Kind::Counted { .. }));
+    let read = payload.iter().map(|field| read_field(field, counted));
+    let field_names = payload.iter().map(|field| {
+        let name = &field.name;
+        match field.kind {
+            Kind::Bytes { .. } => quote!(#name: #name.to_vec()),
+            _ => quote!(#name),
+        }
+    });
+
+    let command = &attributes.command;
+    let payload_length = match &attributes.payload_length {
+        Some(length) => quote!(Some(#length)),
+        None => quote!(None),
+    };
+    let nom = quote!(::hacklet::__private::nom);
+    let read_payload = if counted {
+        quote! {
+            let (__rest, input) = #nom::bytes::streaming::take(payload_length as usize)(input)?;
+            #(#read)*
+            if !input.is_empty() {
+                return Err(#nom::Err::Error(#nom::error::Error::new(input, #nom::error::ErrorKind::Eof)));
+            }
+            let input = __rest;
+        }
+    } else {
+        quote!(#(#read)*)
+    };
+
+    Ok(quote! {
+        impl ::hacklet::messages::Message for #name
+        {
+            fn encode(&self, encoder: &mut ::hacklet::messages::Encoder)
+            {
+                encoder.header(self.header);
+                encoder.bytes(&self.command.to_be_bytes());
+                encoder.u8(self.payload_length);
+                #(#encode)*
+            }
+            fn read(input: &[u8]) -> #nom::IResult<&[u8], Self>
+            {
+                let (input, (header, command, payload_length)) =
+                    ::hacklet::messages::frame_header(#command, #payload_length)(input)?;
+                #read_payload
+                let (input, checksum) = #nom::number::streaming::be_u8(input)?;
+
+                Ok((input, #name { header, command, payload_length, #(#field_names,)* checksum }))
+            }
+        }
+        impl #name
+        {
+            pub fn as_bytes(&self) -> ::hacklet::__private::Vec<u8>
+            {
+                let mut buffer = ::hacklet::__private::Vec::new();
+                buffer.resize(::hacklet::messages::Message::encoded_len(self), 0);
+                ::hacklet::messages::Message::encode_into(self, &mut buffer);
+                buffer
+            }
+            pub fn header(&self) -> u8
+            {
+                self.header
+            }
+            pub fn command(&self) -> u16
+            {
+                self.command
+            }
+            pub fn payload_length(&self) -> u8
+            {
+                self.payload_length
+            }
+            // As received, compare with `calculate_checksum` to validate it
+            pub fn checksum(&self) -> u8
+            {
+                self.checksum
+            }
+        }
+    })
+}
+
+fn message_attributes(input: &DeriveInput) -> Result<MessageAttributes>
+{
+    let mut command = None;
+    let mut payload_length = None;
+    let mut no_payload = false;
+    for attribute in input.attrs.iter().filter(|attribute| attribute.path().is_ident("hacklet")) {
+        attribute.parse_nested_meta(|meta| {
+            if meta.path.is_ident("command") {
+                command = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("payload_length") {
+                payload_length = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("no_payload") {
+                no_payload = true;
+            } else {
+                return Err(meta.error("expected `command`, `payload_length` or `no_payload`"));
+            }
+            Ok(())
+        })?;
+    }
+    let command = command.ok_or_else(|| Error::new(input.span(), "missing #[hacklet(command = ...)]"))?;
+    Ok(MessageAttributes { command, payload_length, no_payload })
+}
+
+// The length byte is all a reader has to find the next frame, a layout that
+// disagrees with it desyncs every stream the message appears in
+fn check_payload_length(input: &DeriveInput, attributes: &MessageAttributes, payload: &[Field]) -> Result<()>
+{
+    let counted = payload.iter().any(|field| matches!(field.kind, Kind::Counted { .. }));
+    let length = match &attributes.payload_length {
+        Some(length) => length,
+        None if counted => return Ok(()),
+        None => return Err(Error::new(input.span(), "missing #[hacklet(payload_length = ...)]")),
+    };
+    let declared = length.base10_parse::<usize>()?;
+    if attributes.no_payload {
+        if !payload.is_empty() {
+            return Err(Error::new(input.span(), "`no_payload` messages can't have payload fields"));
+        }
+        return Ok(());
+    }
+    if counted {
+        return Ok(());
+    }
+
+    let mut width = 0;
+    for field in payload {
+        width += match &field.kind {
+            Kind::Integer { bytes, .. } => *bytes,
+            Kind::Bytes { len } => len.base10_parse::<usize>()?,
+            Kind::Counted { .. } => unreachable!(),
+        };
+    }
+    if width != declared {
+        let message = format!("payload fields add up to {} bytes but payload_length is {}", width, declared);
+        return Err(Error::new(length.span(), message));
+    }
+    Ok(())
+}
+
+fn payload_field(field: &syn::Field) -> Result<Field>
+{
+    let name = field.ident.clone().unwrap();
+    let mut little_endian = false;
+    let mut bytes = None;
+    let mut len = None;
+    let mut count = None;
+    let mut expect = None;
+    for attribute in field.attrs.iter().filter(|attribute| attribute.path().is_ident("hacklet")) {
+        attribute.parse_nested_meta(|meta| {
+            if meta.path.is_ident("le") {
+                little_endian = true;
+            } else if meta.path.is_ident("bytes") {
+                bytes = Some(meta.value()?.parse::<LitInt>()?.base10_parse::<usize>()?);
+            } else if meta.path.is_ident("len") {
+                len = Some(meta.value()?.parse::<LitInt>()?);
+            } else if meta.path.is_ident("count") {
+                count = Some(meta.value()?.parse::<Ident>()?);
+            } else if meta.path.is_ident("expect") {
+                expect = Some(meta.value()?.parse::<Expr>()?);
+            } else {
+                return Err(meta.error("expected `le`, `bytes`, `len`, `count` or `expect`"));
+            }
+            Ok(())
+        })?;
+    }
+
+    let kind = match (vec_element(&field.ty), len, count) {
+        (Some(element), Some(len), None) if unsigned_bits(element) == Some(8) => Kind::Bytes { len },
+        (Some(element), None, Some(count)) => match unsigned_bits(element) {
+            Some(bits) => Kind::Counted { bits, count },
+            None => return Err(Error::new(element.span(), "counted fields must be a Vec of u8, u16, u32 or u64")),
+        },
+        (Some(_), _, _) => {
+            return Err(Error::new(field.ty.span(), "Vec fields need #[hacklet(len = ...)] for Vec<u8> or #[hacklet(count = ...)]"))
+        }
+        (None, None, None) => match unsigned_bits(&field.ty) {
+            Some(bits) => {
+                let bytes = bytes.unwrap_or(bits / 8);
+                if bytes == 0 || bytes > bits / 8 || (bytes != bits / 8 && bytes != 3) {
+                    return Err(Error::new(field.ty.span(), "`bytes` must be 3 or the full width of the field"));
+                }
+                Kind::Integer { bits, bytes }
+            }
+            None => return Err(Error::new(field.ty.span(), "payload fields must be u8, u16, u32, u64 or a Vec")),
+        },
+        (None, _, _) => return Err(Error::new(field.span(), "`len` and `count` only apply to Vec fields")),
+    };
+    Ok(Field { name, kind, little_endian, expect })
+}
+
+fn encode_field(field: &Field) -> TokenStream2
+{
+    let name = &field.name;
+    let to_bytes = if field.little_endian { quote!(to_le_bytes) } else { quote!(to_be_bytes) };
+    match &field.kind {
+        Kind::Integer { bits: 8, .. } => quote!(encoder.u8(self.#name);),
+        Kind::Integer { bits, bytes } if bits / 8 == *bytes => quote!(encoder.bytes(&self.#name.#to_bytes());),
+        // A narrower field keeps its low bytes, which come first in little-endian
+        Kind::Integer { bytes, .. } if field.little_endian => quote!(encoder.bytes(&self.#name.to_le_bytes()[..#bytes]);),
+        Kind::Integer { bits, bytes } => {
+            let skip = bits / 8 - bytes;
+            quote!(encoder.bytes(&self.#name.to_be_bytes()[#skip..]);)
+        }
+        Kind::Bytes { .. } => quote!(encoder.bytes(&self.#name);),
+        Kind::Counted { .. } => quote! {
+            for item in &self.#name {
+                encoder.bytes(&item.#to_bytes());
+            }
+        },
+    }
+}
+
+// `complete` when reading from a payload that has already been taken whole,
+// running out of bytes there means the frame is malformed
+fn read_field(field: &Field, complete: bool) -> TokenStream2
+{
+    let name = &field.name;
+    let nom = quote!(::hacklet::__private::nom);
+    let parser = match &field.kind {
+        Kind::Integer { bits, bytes } => number_parser(field.little_endian, *bits, *bytes),
+        Kind::Bytes { len } => quote!(#nom::bytes::streaming::take(#len as usize)),
+        Kind::Counted { bits, count } => {
+            let element = number_parser(field.little_endian, *bits, bits / 8);
+            quote!(#nom::multi::count(#element, #count as usize))
+        }
+    };
+    let parser = if complete { quote!(#nom::combinator::complete(#parser)) } else { parser };
+    match &field.expect {
+        Some(expected) => quote! {
+            let (input, #name) = #nom::combinator::verify(#parser, |value| *value == #expected)(input)?;
+        },
+        None => quote!(let (input, #name) = #parser(input)?;),
+    }
+}
+
+fn number_parser(little_endian: bool, bits: usize, bytes: usize) -> TokenStream2
+{
+    let endian = if little_endian || bits == 8 { "le" } else { "be" };
+    let parser = Ident::new(&format!("{}_u{}", endian, bytes * 8), Span::call_site());
+    let parser = quote!(::hacklet::__private::nom::number::streaming::#parser);
+    if bytes * 8 == bits {
+        parser
+    } else {
+        let ty = Ident::new(&format!("u{}", bits), Span::call_site());
+        quote!(::hacklet::__private::nom::combinator::map(#parser, |value| value as #ty))
+    }
+}
+
+fn vec_element(ty: &Type) -> Option<&Type>
+{
+    let Type::Path(path) = ty else { return None };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Vec" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(arguments) => match arguments.args.first()? {
+            GenericArgument::Type(element) => Some(element),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn unsigned_bits(ty: &Type) -> Option<usize>
+{
+    let Type::Path(path) = ty else { return None };
+    match path.path.get_ident()?.to_string().as_str() {
+        "u8" => Some(8),
+        "u16" => Some(16),
+        "u32" => Some(32),
+        "u64" => Some(64),
+        _ => None,
+    }
+}
+
@@ -0,0 +1,353 @@
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, ToSocketAddrs},
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    dongle::{Commissioned, DongleError, DongleInfo},
+    messages::{requests::*, responses::*, Message, MAX_FRAME_LEN},
+};
+
+// How long to wait for the dongle to answer a request
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The same operations as `Dongle`, but awaiting the dongle instead of blocking
+// a thread on it. Works over anything that is `AsyncRead + AsyncWrite`, such as
+// a `TcpStream` to `hacklet bridge`.
+//
+// There is no async drop, so there is no guard for unlocked networks here:
+// `commission` locks the network again itself, callers of `unlock_network`
+// have to call `lock_network` when they are done.
+pub struct AsyncDongle<T> {
+    transport: T,
+    receive_buffer: Vec<u8>,
+    selected_network: Option<u16>,
+    info: Option<DongleInfo>,
+}
+
+impl AsyncDongle<TcpStream> {
+    // Connects to a dongle shared with `hacklet bridge` and boots it
+    pub async fn connect<A: ToSocketAddrs>(address: A) -> Result<Self, DongleError> {
+        let stream = TcpStream::connect(address).await.map_err(DongleError::Io)?;
+        stream.set_nodelay(true).map_err(DongleError::Io)?;
+        AsyncDongle::open(stream).await
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncDongle<T> {
+    // Boots the dongle over the given transport
+    pub async fn open(transport: T) -> Result<Self, DongleError> {
+        let mut dongle = AsyncDongle::new(transport);
+        dongle.boot().await?;
+        Ok(dongle)
+    }
+
+    // Wraps a transport to a dongle that has already been booted
+    pub fn new(transport: T) -> Self {
+        AsyncDongle {
+            transport,
+            receive_buffer: Vec::new(),
+            selected_network: None,
+            info: None,
+        }
+    }
+
+    // What the dongle reported when it booted, `None` if it was booted elsewhere
+    pub fn info(&self) -> Option<&DongleInfo> {
+        self.info.as_ref()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    // Commission method - listens for new devices on the network until `count`
+    // devices have been found (or forever when `None`) or the timeout expires
+    pub async fn commission(&mut self, count: Option<usize>, timeout: Duration) -> Result<Commissioned, DongleError> {
+        self.unlock_network().await?;
+        let found = self.listen_and_update(count, timeout).await;
+        // Relock even if listening failed, then report whichever failed first
+        let locked = self.lock_network().await;
+        let found = found?;
+        locked.map(|()| found)
+    }
+
+    // Selects the network
+    pub async fn select_network(&mut self, network_id: u16) -> Result<(), DongleError> {
+        self.transmit(&HandshakeRequest::new(network_id)).await?;
+        self.receive_response::<HandshakeResponse>().await?;
+        self.selected_network = Some(network_id);
+        Ok(())
+    }
+
+    // Selects the network unless it is already the selected one
+    pub async fn ensure_network(&mut self, network_id: u16) -> Result<(), DongleError> {
+        if self.selected_network != Some(network_id) {
+            self.select_network(network_id).await?;
+        }
+        Ok(())
+    }
+
+    // Request samples
+    pub async fn request_samples(&mut self, network_id: u16, channel_id: u16) -> Result<SamplesResponse, DongleError> {
+        info!("Requesting samples");
+        self.transmit(&SamplesRequest::new(network_id, channel_id)).await?;
+        self.receive_response::<AckResponse>().await?;
+
+        let buffer = self.receive_frame(RESPONSE_TIMEOUT).await?;
+        match SamplesResponse::read(&buffer) {
+            Ok((_, response)) if response.command() == 0x40A4 => {
+                info!("{} returned, {} remaining", response.sample_count, response.stored_sample_count);
+                Ok(response)
+            }
+            _ => Err(DongleError::InvalidResponse(buffer)),
+        }
+    }
+
+    // Switch a socket on or off, succeeds once the dongle acknowledges the new schedule
+    pub async fn switch(&mut self, network_id: u16, channel_id: u16, state: bool) -> Result<(), DongleError> {
+        // Schedule requests carry the channel in a single byte
+        let mut request = ScheduleRequest::new(network_id, channel_id as u8);
+        if state {
+            request.always_on();
+            info!("Turning on channel {} on network 0x{:x}", channel_id, network_id);
+        } else {
+            request.always_off();
+            info!("Turning off channel {} on network 0x{:x}", channel_id, network_id);
+        }
+
+        self.transmit(&request).await?;
+        let buffer = self.receive_frame(RESPONSE_TIMEOUT).await?;
+        match ScheduleResponse::read(&buffer) {
+            Ok((_, response)) if response.command() == 0x4023 && response.checksum() == response.calculate_checksum() => Ok(()),
+            _ => Err(DongleError::InvalidResponse(buffer)),
+        }
+    }
+
+    // Unlock the network for pairing, `lock_network` has to follow
+    pub async fn unlock_network(&mut self) -> Result<(), DongleError> {
+        info!("Unlocking network");
+        self.transmit(&UnlockRequest::new()).await?;
+        self.selected_network = None;
+        self.receive_response::<LockResponse>().await?;
+        info!("Unlocking complete");
+        Ok(())
+    }
+
+    // Lock the network
+    pub async fn lock_network(&mut self) -> Result<(), DongleError> {
+        info!("Locking network");
+        self.transmit(&LockRequest::new()).await?;
+        self.receive_response::<LockResponse>().await?;
+        info!("Locking complete");
+        Ok(())
+    }
+
+    // Boot the dongle and confirm boot success
+    async fn boot(&mut self) -> Result<(), DongleError> {
+        info!("Booting");
+        self.transmit(&BootRequest::new()).await?;
+        let buffer = self.receive_frame(RESPONSE_TIMEOUT).await?;
+        let info = match BootResponse::read(&buffer) {
+            Ok((_, response)) if response.command() == 0x4084 => DongleInfo::from_boot(&response),
+            _ => return Err(DongleError::InvalidResponse(buffer)),
+        };
+        self.transmit(&BootConfirmRequest::new()).await?;
+        self.receive_response::<BootConfirmResponse>().await?;
+        info!("Booted dongle {}", info);
+        self.info = Some(info);
+        Ok(())
+    }
+
+    // Update device time
+    async fn update_time(&mut self, network_id: u16) -> Result<(), DongleError> {
+        self.transmit(&UpdateTimeRequest::now(network_id)).await?;
+        self.receive_response::<UpdateTimeAckResponse>().await?;
+        self.receive_response::<UpdateTimeResponse>().await?;
+        Ok(())
+    }
+
+    async fn listen_and_update(&mut self, count: Option<usize>, timeout: Duration) -> Result<Commissioned, DongleError> {
+        let found = self.listen(count, timeout).await?;
+
+        let mut networks: Vec<u16> = found.iter().map(|resp| resp.network_id).collect();
+        networks.sort_unstable();
+        networks.dedup();
+        let mut failed = Vec::new();
+        for network_id in networks {
+            if let Err(err) = self.update_time(network_id).await {
+                warn!("Could not set the time on network 0x{:x}: {}", network_id, err);
+                failed.push((network_id, err));
+            }
+        }
+        Ok(Commissioned { devices: found, failed })
+    }
+
+    async fn listen(&mut self, count: Option<usize>, timeout: Duration) -> Result<Vec<BroadcastResponse>, DongleError> {
+        let mut found: Vec<BroadcastResponse> = Vec::new();
+        let deadline = Instant::now() + timeout;
+        info!("Listening for devices ...");
+
+        while count.is_none_or(|count| found.len() < count) {
+            let buffer = match self.receive_frame_until(deadline).await {
+                Ok(buffer) => buffer,
+                Err(DongleError::Timeout) => break,
+                Err(err) => return Err(err),
+            };
+            if buffer[1] != 0xa0 {
+                continue;
+            }
+
+            match BroadcastResponse::read(&buffer) {
+                Ok((_, resp)) => {
+                    // Devices keep broadcasting while they pair, only report each once
+                    if found.iter().any(|f| f.device_id == resp.device_id) {
+                        continue;
+                    }
+                    info!("Found device 0x{:x} on network 0x{:x}", resp.device_id, resp.network_id);
+                    found.push(resp);
+                }
+                Err(err) => warn!("Ignoring malformed broadcast {:?}: {:?}", buffer, err),
+            }
+        }
+        Ok(found)
+    }
+
+    // Encodes on the stack, so polling does not allocate per request
+    async fn transmit<M: Message>(&mut self, message: &M) -> Result<(), DongleError> {
+        let mut buffer = [0; MAX_FRAME_LEN];
+        let len = message.encode_into(&mut buffer);
+        let bytes = &buffer[..len];
+        log::debug!("TX: {:?}", bytes);
+        self.transport.write_all(bytes).await.map_err(DongleError::Io)?;
+        self.transport.flush().await.map_err(DongleError::Io)
+    }
+
+    // Reads the next frame as the reply `R`, any other frame is an invalid
+    // response
+    async fn receive_response<R: Message>(&mut self) -> Result<R, DongleError> {
+        let buffer = self.receive_frame(RESPONSE_TIMEOUT).await?;
+        match R::read(&buffer) {
+            Ok((_, response)) => Ok(response),
+            Err(_) => Err(DongleError::InvalidResponse(buffer)),
+        }
+    }
+
+    async fn receive_frame(&mut self, timeout: Duration) -> Result<Vec<u8>, DongleError> {
+        self.receive_frame_until(Instant::now() + timeout).await
+    }
+
+    // Reads one whole frame, using its length byte to know where it ends
+    async fn receive_frame_until(&mut self, deadline: Instant) -> Result<Vec<u8>, DongleError> {
+        self.fill_buffer(4, deadline).await?;
+        let length = 4 + self.receive_buffer[3] as usize + 1;
+        self.fill_buffer(length, deadline).await?;
+
+        let frame: Vec<u8> = self.receive_buffer.drain(..length).collect();
+        log::debug!("RX: {:?}", frame);
+        Ok(frame)
+    }
+
+    async fn fill_buffer(&mut self, bytes: usize, deadline: Instant) -> Result<(), DongleError> {
+        let mut chunk = [0u8; 64];
+        while self.receive_buffer.len() < bytes {
+            let read = timeout_at(deadline, self.transport.read(&mut chunk))
+                .await
+                .map_err(|_| DongleError::Timeout)?
+                .map_err(DongleError::Io)?;
+            if read == 0 {
+                return Err(DongleError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.receive_buffer.extend_from_slice(&chunk[..read]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    // Plays the dongle's side of the conversation: expects each request in
+    // turn and answers it with the given frames
+    async fn dongle(mut stream: DuplexStream, exchanges: Vec<(Vec<u8>, Vec<Vec<u8>>)>) {
+        for (request, responses) in exchanges {
+            let mut received = vec![0u8; request.len()];
+            stream.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, request);
+            for response in responses {
+                stream.write_all(&response).await.unwrap();
+            }
+        }
+    }
+
+    fn boot() -> Vec<(Vec<u8>, Vec<Vec<u8>>)> {
+        vec![
+            (BootRequest::new().as_bytes(), vec![BootResponse::new(vec![0; 12], 0x0123_4567_89ab_cdef, 0).as_bytes()]),
+            (BootConfirmRequest::new().as_bytes(), vec![BootConfirmResponse::new().as_bytes()]),
+        ]
+    }
+
+    #[tokio_macros::test]
+    async fn boots_and_switches_a_socket() {
+        let mut request = ScheduleRequest::new(0x1234, 1);
+        request.always_on();
+        let mut exchanges = boot();
+        exchanges.push((request.as_bytes(), vec![ScheduleResponse::new().as_bytes()]));
+
+        let (client, server) = duplex(256);
+        let server = tokio::spawn(dongle(server, exchanges));
+
+        let mut dongle = AsyncDongle::open(client).await.unwrap();
+        assert_eq!(dongle.info().unwrap().device_id(), 0x0123_4567_89ab_cdef);
+        dongle.switch(0x1234, 1, true).await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio_macros::test]
+    async fn reads_samples() {
+        let samples = vec![0x0a05, 0x0b07];
+        let response = SamplesResponse::new(0x1234, 1, 0x0000, 0, 0, samples.clone());
+
+        let exchanges = vec![(
+            SamplesRequest::new(0x1234, 1).as_bytes(),
+            vec![AckResponse::new().as_bytes(), response.as_bytes()],
+        )];
+        let (client, server) = duplex(256);
+        let server = tokio::spawn(dongle(server, exchanges));
+
+        let mut dongle = AsyncDongle::new(client);
+        let response = dongle.request_samples(0x1234, 1).await.unwrap();
+        assert_eq!(response.samples, samples);
+        server.await.unwrap();
+    }
+
+    #[tokio_macros::test]
+    async fn rejects_a_reply_to_another_request() {
+        let exchanges = vec![
+            (HandshakeRequest::new(0x1234).as_bytes(), vec![LockResponse::new().as_bytes()]),
+            (LockRequest::new().as_bytes(), vec![HandshakeResponse::new().as_bytes()]),
+        ];
+        let (client, server) = duplex(256);
+        let server = tokio::spawn(dongle(server, exchanges));
+
+        let mut dongle = AsyncDongle::new(client);
+        assert!(matches!(dongle.select_network(0x1234).await, Err(DongleError::InvalidResponse(_))));
+        assert!(matches!(dongle.lock_network().await, Err(DongleError::InvalidResponse(_))));
+        // A network that didn't answer the handshake is not selected
+        assert_eq!(dongle.selected_network, None);
+        server.await.unwrap();
+    }
+
+    #[tokio_macros::test(start_paused = true)]
+    async fn times_out_when_the_dongle_is_silent() {
+        let (client, _server) = duplex(256);
+        let mut dongle = AsyncDongle::new(client);
+        assert!(matches!(dongle.lock_network().await, Err(DongleError::Timeout)));
+    }
+}
@@ -0,0 +1,139 @@
+use log::{info, warn};
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    thread::sleep,
+    time::Duration,
+};
+
+use crate::transport::Transport;
+
+// TCP-to-serial bridge - exposes the dongle's raw byte stream on a TCP port so
+// it can be driven from another machine with `--device tcp://host:port`.
+// One client at a time, further clients wait in the listen backlog until the
+// current one disconnects. The dongle stays open between clients.
+
+// How long to wait when neither side had anything to say
+const IDLE_POLL: Duration = Duration::from_millis(5);
+
+pub fn serve(listener: &TcpListener, transport: &mut dyn Transport) -> io::Result<()>
+{
+    info!("Bridging the dongle on {}", listener.local_addr()?);
+    loop {
+        let (stream, peer) = listener.accept()?;
+        info!("Client {} connected", peer);
+        match bridge_client(stream, transport) {
+            Ok(()) => info!("Client {} disconnected", peer),
+            Err(err) if is_disconnect(&err) => warn!("Client {} dropped: {}", peer, err),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Pumps bytes both ways until the client hangs up. Errors from the dongle end
+// the bridge, errors from the client only end this connection.
+pub fn bridge_client(mut stream: TcpStream, transport: &mut dyn Transport) -> io::Result<()>
+{
+    stream.set_nonblocking(true)?;
+    stream.set_nodelay(true)?;
+    let mut buffer = [0u8; 64];
+
+    loop {
+        let mut idle = true;
+
+        match stream.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => {
+                transport.write(&buffer[..read])?;
+                idle = false;
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+
+        let read = transport.read(&mut buffer)?;
+        if read > 0 {
+            write_all_nonblocking(&mut stream, &buffer[..read])?;
+            idle = false;
+        }
+
+        if idle {
+            sleep(IDLE_POLL);
+        }
+    }
+}
+
+fn write_all_nonblocking(stream: &mut TcpStream, mut data: &[u8]) -> io::Result<()>
+{
+    while !data.is_empty() {
+        match stream.write(data) {
+            Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "client stopped reading")),
+            Ok(written) => data = &data[written..],
+            Err(err) if err.kind() == ErrorKind::WouldBlock => sleep(IDLE_POLL),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn is_disconnect(err: &io::Error) -> bool
+{
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::WriteZero
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        capture::Direction,
+        dongle::Dongle,
+        messages::{requests::ScheduleRequest, responses::ScheduleResponse},
+        replay::ReplayTransport,
+        serial_connection::SerialConnection,
+        tcp::TcpTransport,
+    };
+
+    // A dongle that goes quiet once the replay is over instead of erroring
+    struct Quiet(ReplayTransport);
+
+    impl Transport for Quiet {
+        fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            self.0.write(data)
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_finished() {
+                return Ok(0);
+            }
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn drives_a_dongle_over_tcp() {
+        let mut request = ScheduleRequest::new(0x1234, 1);
+        request.always_on();
+        let frames = vec![
+            (Direction::Tx, request.as_bytes()),
+            (Direction::Rx, ScheduleResponse::new().as_bytes()),
+        ];
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let bridge = std::thread::spawn(move || {
+            let mut transport = Quiet(ReplayTransport::from_frames(frames));
+            let (stream, _) = listener.accept().unwrap();
+            bridge_client(stream, &mut transport).map(|()| transport.0.is_finished())
+        });
+
+        let transport = TcpTransport::connect(address).unwrap();
+        let mut dongle = Dongle::new(SerialConnection::with_transport(Box::new(transport)));
+        dongle.switch(0x1234, 1, true).unwrap();
+        drop(dongle);
+
+        assert!(bridge.join().unwrap().unwrap());
+    }
+}
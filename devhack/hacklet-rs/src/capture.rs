@@ -0,0 +1,187 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufWriter, ErrorKind, Write},
+    path::Path,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::messages::parse_hex;
+
+// Wire captures (.hcap) - every chunk written to or read from the dongle,
+// stored as JSON lines so they can be read with standard tools:
+//
+//     {"hcap":1,"start":1700000000123456}
+//     {"t":0,"dir":"tx","data":"0240040044"}
+//     {"t":1520,"dir":"rx","data":"0240841601"}
+//
+// The first line is a header with the format version and the wall clock start
+// time in microseconds since the Unix epoch. Every other line is one chunk:
+// `t` is microseconds since the start of the capture (monotonic), `dir` is
+// `tx` for bytes sent to the dongle and `rx` for bytes received from it, and
+// `data` is the chunk in hex. RX chunks are recorded as they arrive from the
+// device, so a frame may be split across several of them.
+
+pub const CAPTURE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Tx => write!(f, "tx"),
+            Direction::Rx => write!(f, "rx"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CaptureWriter {
+    file: BufWriter<File>,
+    start: Instant,
+}
+
+impl CaptureWriter {
+    pub fn create(path: &Path) -> io::Result<CaptureWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let start_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_micros();
+        writeln!(file, "{{\"hcap\":{},\"start\":{}}}", CAPTURE_VERSION, start_time)?;
+        file.flush()?;
+
+        Ok(CaptureWriter {
+            file,
+            start: Instant::now(),
+        })
+    }
+
+    // Appends one chunk, flushed straight away so a crash doesn't lose the
+    // frames leading up to it
+    pub fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        let hex: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+        writeln!(
+            self.file,
+            "{{\"t\":{},\"dir\":\"{}\",\"data\":\"{}\"}}",
+            self.start.elapsed().as_micros(),
+            direction,
+            hex
+        )?;
+        self.file.flush()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureEvent {
+    // Microseconds since the start of the capture
+    pub time: u64,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capture {
+    // Wall clock start time in microseconds since the Unix epoch
+    pub start: u64,
+    pub events: Vec<CaptureEvent>,
+}
+
+impl Capture {
+    pub fn load(path: &Path) -> io::Result<Capture> {
+        Capture::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(contents: &str) -> io::Result<Capture> {
+        let mut lines = contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+        let invalid = |line: usize, reason: &str| {
+            io::Error::new(ErrorKind::InvalidData, format!("capture line {}: {}", line + 1, reason))
+        };
+
+        let (index, header) = lines.next().ok_or_else(|| invalid(0, "empty capture"))?;
+        match field(header, "hcap").and_then(|version| version.parse::<u32>().ok()) {
+            Some(CAPTURE_VERSION) => {}
+            Some(version) => return Err(invalid(index, &format!("unsupported capture version {}", version))),
+            None => return Err(invalid(index, "missing capture header")),
+        }
+        let start = field(header, "start")
+            .and_then(|start| start.parse().ok())
+            .ok_or_else(|| invalid(index, "invalid start time"))?;
+
+        let mut events = Vec::new();
+        for (index, line) in lines {
+            let time = field(line, "t").and_then(|time| time.parse().ok());
+            let direction = match field(line, "dir") {
+                Some("tx") => Some(Direction::Tx),
+                Some("rx") => Some(Direction::Rx),
+                _ => None,
+            };
+            let data = field(line, "data").and_then(parse_hex);
+            match (time, direction, data) {
+                (Some(time), Some(direction), Some(data)) => events.push(CaptureEvent { time, direction, data }),
+                _ => return Err(invalid(index, "invalid chunk")),
+            }
+        }
+
+        Ok(Capture { start, events })
+    }
+}
+
+// Pulls a value out of one of our own single line JSON objects. This is not a
+// general JSON parser, it only understands what `CaptureWriter` writes.
+fn field<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let key = format!("\"{}\":", name);
+    let rest = &line[line.find(&key)? + key.len()..];
+    let end = rest.find([',', '}'])?;
+    Some(rest[..end].trim().trim_matches('"'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_a_header_and_one_line_per_chunk() {
+        let path = std::env::temp_dir().join(format!("hacklet-capture-{}.hcap", std::process::id()));
+        let mut capture = CaptureWriter::create(&path).unwrap();
+        capture.record(Direction::Tx, &[0x02, 0x40, 0x04, 0x00, 0x44]).unwrap();
+        capture.record(Direction::Rx, &[0x02, 0x40]).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("{\"hcap\":1,\"start\":"));
+        assert!(lines[1].ends_with("\"dir\":\"tx\",\"data\":\"0240040044\"}"));
+        assert!(lines[2].ends_with("\"dir\":\"rx\",\"data\":\"0240\"}"));
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let path = std::env::temp_dir().join(format!("hacklet-capture-read-{}.hcap", std::process::id()));
+        let mut capture = CaptureWriter::create(&path).unwrap();
+        capture.record(Direction::Tx, &[0x02, 0x40, 0x04, 0x00, 0x44]).unwrap();
+        capture.record(Direction::Rx, &[0x02, 0x40]).unwrap();
+
+        let loaded = Capture::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(loaded.start > 0);
+        let events: Vec<(Direction, Vec<u8>)> = loaded.events.into_iter().map(|e| (e.direction, e.data)).collect();
+        assert_eq!(
+            events,
+            vec![(Direction::Tx, vec![0x02, 0x40, 0x04, 0x00, 0x44]), (Direction::Rx, vec![0x02, 0x40])]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let err = Capture::parse("{\"hcap\":2,\"start\":0}\n").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,6 +1,31 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::Ordering,
+    time::Duration,
+};
+
 use argh::FromArgs;
-use log::{info, debug};
-use crate::dongle::Dongle;
+use log::{info, debug, warn};
+use hacklet::bridge;
+use hacklet::capture::Capture;
+use hacklet::decode;
+use hacklet::dongle::{Dongle, DongleError, DongleInfo};
+use hacklet::ftdi::{self, FtdiTransport};
+use hacklet::messages::{frame::Frame, parse_hex, responses::BroadcastResponse, to_hex};
+use hacklet::pcapng;
+use hacklet::replay::ReplayTransport;
+use hacklet::serial_connection::SerialConnection;
+use hacklet::tcp::{self, TcpTransport};
+use hacklet::transport::Transport;
+
+use crate::registry::Registry;
+use crate::script::{self, Step};
+use crate::shell;
+use crate::version::VERSION;
 
 /// Hacklet CLI - Manage your smart sockets and devices.
 #[derive(FromArgs)]
@@ -9,6 +34,26 @@ pub struct Hacklet {
     #[argh(switch, short = 'd')]
     pub debug: bool,
 
+    /// path to the device registry (default ~/.hacklet/registry)
+    #[argh(option)]
+    pub registry: Option<PathBuf>,
+
+    /// record every byte sent to and received from the dongle to a capture file
+    #[argh(option)]
+    pub record: Option<PathBuf>,
+
+    /// play a capture file back instead of talking to a real dongle
+    #[argh(option)]
+    pub replay: Option<PathBuf>,
+
+    /// talk to a dongle shared with `hacklet bridge` (ex. tcp://closet:7070)
+    #[argh(option)]
+    pub device: Option<String>,
+
+    /// which attached dongle to use: serial number, #index or bus/device (default the one the network was commissioned with, or the first found)
+    #[argh(option)]
+    pub dongle: Option<String>,
+
     #[argh(subcommand)]
     pub command: Commands,
 }
@@ -20,13 +65,23 @@ pub enum Commands {
     Off(OffCommand),
     Read(ReadCommand),
     Commission(CommissionCommand),
+    Decommission(DecommissionCommand),
+    Scene(SceneCommand),
+    Batch(BatchCommand),
+    Shell(ShellCommand),
+    Raw(RawCommand),
+    Decode(DecodeCommand),
+    Export(ExportCommand),
+    Bridge(BridgeCommand),
+    Dongles(DonglesCommand),
+    Info(InfoCommand),
 }
 
 /// Turn on the specified socket.
 #[derive(FromArgs)]
 #[argh(subcommand, name = "on")]
 pub struct OnCommand {
-    /// the network id (ex. 0x1234)
+    /// the network name or id (ex. office or 0x1234)
     #[argh(option, short = 'n')]
     pub network: String,
 
@@ -39,7 +94,7 @@ pub struct OnCommand {
 #[derive(FromArgs)]
 #[argh(subcommand, name = "off")]
 pub struct OffCommand {
-    /// the network id (ex. 0x1234)
+    /// the network name or id (ex. office or 0x1234)
     #[argh(option, short = 'n')]
     pub network: String,
 
@@ -52,7 +107,7 @@ pub struct OffCommand {
 #[derive(FromArgs)]
 #[argh(subcommand, name = "read")]
 pub struct ReadCommand {
-    /// the network id (ex. 0x1234)
+    /// the network name or id (ex. office or 0x1234)
     #[argh(option, short = 'n')]
     pub network: String,
 
@@ -61,145 +116,691 @@ pub struct ReadCommand {
     pub socket: String,
 }
 
-/// Add a new device to the network.
+/// Add new devices to the network.
 #[derive(FromArgs)]
 #[argh(subcommand, name = "commission")]
-pub struct CommissionCommand {}
+pub struct CommissionCommand {
+    /// stop after this many devices have been found (default 1)
+    #[argh(option, short = 'c')]
+    pub count: Option<usize>,
+
+    /// keep listening for devices until the timeout expires, instead of --count
+    #[argh(switch)]
+    pub until_timeout: bool,
+
+    /// how long to listen for devices in seconds (default 30)
+    #[argh(option, short = 't', default = "30")]
+    pub timeout: u64,
+}
+
+/// Remove a network from the device registry. The Modlet keeps its pairing
+/// until it is reset.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "decommission")]
+pub struct DecommissionCommand {
+    /// the network name or id (ex. office or 0x1234)
+    #[argh(option, short = 'n')]
+    pub network: String,
+}
+
+/// Work with scenes from the device registry.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "scene")]
+pub struct SceneCommand {
+    #[argh(subcommand)]
+    pub command: SceneCommands,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum SceneCommands {
+    Apply(SceneApplyCommand),
+    List(SceneListCommand),
+}
+
+/// Switch every socket in a scene in a single dongle session.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "apply")]
+pub struct SceneApplyCommand {
+    /// the scene name (ex. night)
+    #[argh(positional)]
+    pub name: String,
+}
+
+/// List the scenes in the device registry.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list")]
+pub struct SceneListCommand {}
+
+/// Run a script of commands in a single dongle session, one per line:
+/// `on office/0`, `off 0x1234/1`, `read lab/1` or `sleep 5s`.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "batch")]
+pub struct BatchCommand {
+    /// the script to run, read from stdin if omitted or "-"
+    #[argh(positional)]
+    pub script: Option<String>,
+}
+
+/// Start an interactive shell that keeps the dongle booted between commands.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "shell")]
+pub struct ShellCommand {}
+
+/// Send a frame built from a command code and payload, then print every frame
+/// that comes back. The header, length and checksum are filled in.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "raw")]
+pub struct RawCommand {
+    /// the command code (ex. 0x4024)
+    #[argh(option)]
+    pub cmd: String,
+
+    /// the payload in hex (ex. 12340000), further hex words may follow
+    #[argh(option)]
+    pub payload: Option<String>,
+
+    /// how long to wait for frames to come back in seconds (default 2)
+    #[argh(option, short = 't', default = "2")]
+    pub timeout: u64,
+
+    #[argh(positional)]
+    pub rest: Vec<String>,
+}
+
+/// Split a capture or hex bytes into frames and print their fields.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "decode")]
+pub struct DecodeCommand {
+    /// decode these hex bytes instead of a capture (ex. "02 40 84 ...")
+    #[argh(option)]
+    pub hex: Option<String>,
+
+    /// the capture file recorded with --record
+    #[argh(positional)]
+    pub capture: Option<PathBuf>,
+}
+
+/// Export a capture to pcapng, one packet per frame, for Wireshark and
+/// other packet tools.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "export")]
+pub struct ExportCommand {
+    /// the capture file recorded with --record
+    #[argh(positional)]
+    pub capture: PathBuf,
+
+    /// where to write the pcapng file (default the capture with a .pcapng extension)
+    #[argh(option, short = 'o')]
+    pub output: Option<PathBuf>,
+}
+
+/// Share the dongle over TCP so it can be used from another machine with
+/// `--device tcp://host:port`.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "bridge")]
+pub struct BridgeCommand {
+    /// the address to listen on (default 0.0.0.0:7070)
+    #[argh(option, default = "String::from(\"0.0.0.0:7070\")")]
+    pub listen: String,
+}
+
+/// List the dongles attached to this machine.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "dongles")]
+pub struct DonglesCommand {}
+
+/// Boot the dongle and print what it reports about itself.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "info")]
+pub struct InfoCommand {}
 
 pub fn command() {
     let args: Hacklet = argh::from_env();
-    
-    // Initialize the dongle
-    Dongle::open(|dongle| {
+
+    // Decoding and exporting work on files, the bridge only moves bytes and listing
+    // dongles doesn't open them, none of these need the registry or a booted dongle
+    match &args.command {
+        Commands::Decode(cmd) => return decode(cmd),
+        Commands::Export(cmd) => return export(cmd),
+        Commands::Bridge(cmd) => return bridge(cmd, args.replay.as_deref(), args.dongle.as_deref()),
+        Commands::Dongles(_) => return list_dongles(),
+        _ => {}
+    }
+
+    let registry_path = args.registry.clone().unwrap_or_else(Registry::default_path);
+    let mut registry = Registry::load(&registry_path).unwrap_or_else(|err| {
+        eprintln!("Could not load device registry: {}", err);
+        process::exit(1);
+    });
+
+    // Registry only commands don't need the dongle
+    if let Commands::Decommission(cmd) = &args.command {
+        let network_id = resolve_network(&registry, &cmd.network);
+        match registry.remove_network(network_id) {
+            Some(network) => {
+                save_registry(&registry);
+                println!("Removed network {} (0x{:04x})", network.name, network.network_id);
+            }
+            None => {
+                eprintln!("Network 0x{:04x} is not registered", network_id);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Commands::Scene(SceneCommand { command: SceneCommands::List(_) }) = &args.command {
+        for scene in registry.scenes() {
+            let actions: Vec<String> = scene
+                .actions
+                .iter()
+                .map(|(member, state)| format!("{}={}", member, if *state { "on" } else { "off" }))
+                .collect();
+            println!("{}: {}", scene.name, actions.join(" "));
+        }
+        return;
+    }
+
+    // Resolve scenes and scripts up front so a typo doesn't cost a dongle boot
+    let steps = match &args.command {
+        Commands::Scene(SceneCommand { command: SceneCommands::Apply(cmd) }) => {
+            let actions = registry.scene_actions(&cmd.name).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                process::exit(1);
+            });
+            actions.into_iter().map(|(socket, state)| Step::Switch(socket, state)).collect()
+        }
+        Commands::Batch(cmd) => {
+            let script = read_script(cmd.script.as_deref()).unwrap_or_else(|err| {
+                eprintln!("Could not read script: {}", err);
+                process::exit(1);
+            });
+            script::parse_script(&registry, &script).unwrap_or_else(|err| {
+                eprintln!("Invalid script: {}", err);
+                process::exit(1);
+            })
+        }
+        _ => Vec::new(),
+    };
+
+    let raw_frame = match &args.command {
+        Commands::Raw(cmd) => Some(build_raw_frame(cmd)),
+        _ => None,
+    };
+
+    if args.device.is_some() && args.dongle.is_some() {
+        eprintln!("Use either --device or --dongle, not both");
+        process::exit(1);
+    }
+    if let Commands::Commission(CommissionCommand { count: Some(_), until_timeout: true, .. }) = &args.command {
+        eprintln!("Use either --count or --until-timeout, not both");
+        process::exit(1);
+    }
+    // How to open the dongle again if it is lost while the command runs,
+    // a capture can only be played back once
+    let mut reconnect: Option<Box<dyn FnMut() -> io::Result<SerialConnection>>> = None;
+    let mut serial = match (&args.replay, &args.device) {
+        (Some(path), _) => SerialConnection::with_transport(Box::new(open_replay(path))),
+        (None, Some(device)) => {
+            let transport = connect_device(device);
+            let device = device.clone();
+            reconnect = Some(Box::new(move || {
+                let address = tcp::parse_device(&device).expect("checked by connect_device");
+                Ok(SerialConnection::with_transport(Box::new(TcpTransport::connect(address)?)))
+            }));
+            SerialConnection::with_transport(Box::new(transport))
+        }
+        (None, None) => {
+            // Without --dongle, use the one the target networks were commissioned with.
+            // Its bus path changes when it is replugged, so it is looked up again.
+            let selected = args.dongle.clone();
+            let device_id = match selected {
+                Some(_) => None,
+                None => registered_dongle(&registry, &target_networks(&registry, &args.command, &steps)),
+            };
+            let find = move || selected.clone().or_else(|| device_id.and_then(find_dongle));
+            let transport = open_local(find().as_deref());
+            reconnect = Some(Box::new(move || {
+                Ok(SerialConnection::with_transport(Box::new(reopen_local(find().as_deref())?)))
+            }));
+            SerialConnection::with_transport(Box::new(transport))
+        }
+    };
+    if let Some(path) = &args.record {
+        if let Err(err) = serial.record_to(path) {
+            eprintln!("Could not create capture file {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    }
+
+    // Initialize the dongle. The command reports how it went through
+    // `outcome`, so the dongle is closed (and its network relocked) before
+    // the process exits.
+    let mut outcome = Ok(());
+    let opened = Dongle::open_with(serial, |dongle| {
         // Enable debug logging if specified
         if args.debug {
             debug!("Debug logging enabled");
         }
 
-        // Match subcommands
-        match args.command {
-            Commands::On(cmd) => {
-                let network_id = u16::from_str_radix(&cmd.network[2..], 16).unwrap();
-                let socket_id = cmd.socket.parse::<u16>().unwrap();
-                
-                dongle.lock_network();
-                dongle.select_network(network_id);
-                dongle.switch(network_id, socket_id, true);
-                info!("Turned on network 0x{:x}, socket {}", network_id, socket_id);
-            }
-            Commands::Off(cmd) => {
-                let network_id = u16::from_str_radix(&cmd.network[2..], 16).unwrap();
-                let socket_id = cmd.socket.parse::<u16>().unwrap();
-                
-                dongle.lock_network();
-                dongle.select_network(network_id);
-                dongle.switch(network_id, socket_id, false);
-                info!("Turned off network 0x{:x}, socket {}", network_id, socket_id);
+        if let Some(reconnect) = reconnect {
+            dongle.set_connect(reconnect);
+        }
+
+        // Let a Ctrl-C stop the command cleanly so it can relock the network.
+        // Every wait on the dongle is bounded, so pressing it again only
+        // repeats that it is finishing up.
+        let interrupted = dongle.interrupt_flag();
+        let handler = ctrlc::set_handler(move || {
+            interrupted.store(true, Ordering::SeqCst);
+            eprintln!("Interrupted, finishing up");
+        });
+        if let Err(err) = handler {
+            warn!("Could not install Ctrl-C handler: {}", err);
+        }
+
+        // Match subcommands, a lost connection to the dongle ends any of them
+        outcome = (|| -> Result<(), Failure> {
+            match args.command {
+                Commands::On(cmd) => {
+                    let network_id = resolve_network(&registry, &cmd.network);
+                    let socket_id = cmd.socket.parse::<u16>().unwrap();
+                    
+                    dongle.retrying(|dongle| {
+                        dongle.lock_network()?;
+                        dongle.select_network(network_id)
+                    })?;
+                    let switched = dongle.retrying(|dongle| {
+                        dongle.ensure_network(network_id)?;
+                        dongle.switch(network_id, socket_id, true)
+                    });
+                    if let Err(err) = switched {
+                        eprintln!("Could not turn on network 0x{:x}, socket {}: {}", network_id, socket_id, err);
+                        return Err(Failure::Reported);
+                    }
+                    info!("Turned on network 0x{:x}, socket {}", network_id, socket_id);
+                }
+                Commands::Off(cmd) => {
+                    let network_id = resolve_network(&registry, &cmd.network);
+                    let socket_id = cmd.socket.parse::<u16>().unwrap();
+                    
+                    dongle.retrying(|dongle| {
+                        dongle.lock_network()?;
+                        dongle.select_network(network_id)
+                    })?;
+                    let switched = dongle.retrying(|dongle| {
+                        dongle.ensure_network(network_id)?;
+                        dongle.switch(network_id, socket_id, false)
+                    });
+                    if let Err(err) = switched {
+                        eprintln!("Could not turn off network 0x{:x}, socket {}: {}", network_id, socket_id, err);
+                        return Err(Failure::Reported);
+                    }
+                    info!("Turned off network 0x{:x}, socket {}", network_id, socket_id);
+                }
+                Commands::Read(cmd) => {
+                    let network_id = resolve_network(&registry, &cmd.network);
+                    let socket_id = cmd.socket.parse::<u16>().unwrap();
+                    
+                    let _samples = dongle.retrying(|dongle| {
+                        dongle.lock_network()?;
+                        dongle.select_network(network_id)?;
+                        dongle.request_samples(network_id, socket_id)
+                    })?;
+                    info!("Read samples from network 0x{:x}, socket {}", network_id, socket_id);
+                }
+                Commands::Commission(cmd) => {
+                    let count = if cmd.until_timeout { None } else { Some(cmd.count.unwrap_or(1)) };
+
+                    info!("Commissioning new devices...");
+                    let commissioned = dongle.commission(count, Duration::from_secs(cmd.timeout))?;
+                    print_commissioned(&commissioned.devices);
+
+                    let dongle_id = dongle.info().map(|info| info.device_id());
+                    for device in &commissioned.devices {
+                        let name = format!("net-{:04x}", device.network_id);
+                        if registry.add_network(&name, device.network_id) {
+                            info!("Registered network 0x{:04x} as {}", device.network_id, name);
+                        }
+                        if let Some(dongle_id) = dongle_id {
+                            registry.set_dongle(device.network_id, dongle_id);
+                        }
+                    }
+                    save_registry(&registry);
+
+                    if !commissioned.failed.is_empty() {
+                        for (network_id, err) in &commissioned.failed {
+                            eprintln!("Could not set the time on network 0x{:04x}: {}", network_id, err);
+                        }
+                        return Err(Failure::Reported);
+                    }
+                }
+                Commands::Scene(_) | Commands::Batch(_) => {
+                    let failures = script::run(dongle, &steps)?;
+                    if failures > 0 {
+                        eprintln!("{} of {} step(s) failed", failures, steps.len());
+                        return Err(Failure::Reported);
+                    }
+                }
+                Commands::Shell(_) => shell::run(dongle, &registry),
+                Commands::Info(_) => {
+                    if let Some(info) = dongle.info() {
+                        print_info(info, &registry);
+                    }
+                }
+                Commands::Raw(cmd) => {
+                    let bytes = raw_frame.expect("built before opening the dongle").as_bytes();
+                    shell::print_frame("TX", &bytes);
+                    for frame in dongle.raw(&bytes, Duration::from_secs(cmd.timeout))? {
+                        shell::print_frame("RX", &frame);
+                    }
+                }
+                Commands::Decommission(_) | Commands::Decode(_) | Commands::Export(_) | Commands::Bridge(_) | Commands::Dongles(_) => unreachable!("handled before opening the dongle"),
             }
-            Commands::Read(cmd) => {
-                let network_id = u16::from_str_radix(&cmd.network[2..], 16).unwrap();
-                let socket_id = cmd.socket.parse::<u16>().unwrap();
-                
-                dongle.lock_network();
-                dongle.select_network(network_id);
-                let _samples = dongle.request_samples(network_id, socket_id);
-                info!("Read samples from network 0x{:x}, socket {}", network_id, socket_id);
+            Ok(())
+        })();
+    });
+    if let Err(err) = opened {
+        eprintln!("Could not boot the dongle: {}", err);
+        process::exit(1);
+    }
+
+    match outcome {
+        Ok(()) => {}
+        Err(Failure::Reported) => process::exit(1),
+        Err(Failure::Dongle(DongleError::Interrupted)) => process::exit(130),
+        Err(Failure::Dongle(err)) if err.is_connection_lost() => {
+            eprintln!("Lost the dongle: {}", err);
+            process::exit(1);
+        }
+        Err(Failure::Dongle(err)) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+}
+
+// Why a command run on the dongle failed
+enum Failure {
+    // The command already told the user what went wrong
+    Reported,
+    Dongle(DongleError),
+}
+
+impl From<DongleError> for Failure {
+    fn from(err: DongleError) -> Self {
+        Failure::Dongle(err)
+    }
+}
+
+fn print_info(info: &DongleInfo, registry: &Registry) {
+    println!("Hacklet:    {}", VERSION);
+    println!("Device id:  {:016x}", info.device_id());
+    println!("Data:       {}", to_hex(info.data()));
+    println!("Data2:      0x{:04x}", info.data2());
+
+    let networks: Vec<String> = registry
+        .networks()
+        .iter()
+        .filter(|network| network.dongle == Some(info.device_id()))
+        .map(|network| format!("{} (0x{:04x})", network.name, network.network_id))
+        .collect();
+    if !networks.is_empty() {
+        println!("Networks:   {}", networks.join(", "));
+    }
+}
+
+fn build_raw_frame(cmd: &RawCommand) -> Frame {
+    let payload: Vec<&str> = cmd.payload.iter().chain(cmd.rest.iter()).map(String::as_str).collect();
+    shell::raw_frame(&cmd.cmd, &payload).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    })
+}
+
+// Prints the frames of a capture file or of hex bytes pasted on the command line
+fn decode(cmd: &DecodeCommand) {
+    let frames = match (&cmd.hex, &cmd.capture) {
+        (Some(hex), None) => match parse_hex(hex) {
+            Some(bytes) => decode::decode_bytes(&bytes),
+            None => {
+                eprintln!("Invalid hex '{}'", hex);
+                process::exit(1);
             }
-            Commands::Commission(_) => {
-                dongle.commission();
-                info!("Commissioning new devices...");
+        },
+        (None, Some(path)) => match Capture::load(path) {
+            Ok(capture) => decode::decode_capture(&capture),
+            Err(err) => {
+                eprintln!("Could not read capture {}: {}", path.display(), err);
+                process::exit(1);
             }
+        },
+        _ => {
+            eprintln!("Give either a capture file or --hex");
+            process::exit(1);
         }
-    });
+    };
+
+    for frame in frames {
+        println!("{}", frame);
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::command::*;
-    use mockall::predicate::*;
-    use mockall::mock;
+fn export(cmd: &ExportCommand) {
+    let capture = Capture::load(&cmd.capture).unwrap_or_else(|err| {
+        eprintln!("Could not read capture {}: {}", cmd.capture.display(), err);
+        process::exit(1);
+    });
+    let output = cmd.output.clone().unwrap_or_else(|| cmd.capture.with_extension("pcapng"));
 
-    // Mock the Dongle struct
-    mock! {
-        pub Dongle {
-            fn lock_network(&self);
-            fn select_network(&self, network_id: u16);
-            fn switch(&self, network_id: u16, socket_id: u16, state: bool);
-            fn request_samples(&self, network_id: u16, socket_id: u16);
-            fn commission(&self);
+    let result = File::create(&output).and_then(|file| pcapng::export(&capture, BufWriter::new(file)));
+    match result {
+        Ok(packets) => println!("Wrote {} packet(s) to {}", packets, output.display()),
+        Err(err) => {
+            eprintln!("Could not write {}: {}", output.display(), err);
+            process::exit(1);
         }
     }
+}
 
-    #[test]
-    fn test_turn_on_socket() {
-        let mut dongle = MockDongle::new();
+fn bridge(cmd: &BridgeCommand, replay: Option<&Path>, dongle: Option<&str>) {
+    let listener = TcpListener::bind(&cmd.listen).unwrap_or_else(|err| {
+        eprintln!("Could not listen on {}: {}", cmd.listen, err);
+        process::exit(1);
+    });
 
-        dongle.expect_lock_network().times(1);
-        dongle.expect_select_network().with(eq(0x0010)).times(1);
-        dongle.expect_switch().with(eq(0x0010), eq(1), eq(true)).times(1);
+    let mut transport: Box<dyn Transport> = match replay {
+        Some(path) => Box::new(open_replay(path)),
+        None => Box::new(open_local(dongle)),
+    };
+    if let Err(err) = bridge::serve(&listener, transport.as_mut()) {
+        eprintln!("Bridge stopped: {}", err);
+        process::exit(1);
+    }
+}
 
-        // Create a dummy command object to run the command method
-        let args = Hacklet {
-            debug: false,
-            command: Commands::On(OnCommand {
-                network: "0x0010".to_string(),
-                socket: "1".to_string(),
-            }),
-        };
+fn list_dongles() {
+    let dongles = ftdi::list().unwrap_or_else(|err| {
+        eprintln!("Could not list dongles: {}", err);
+        process::exit(1);
+    });
+    if dongles.is_empty() {
+        println!("No dongles found");
+        return;
+    }
 
-        // Call the command function
-        command();
+    // Any of index, path and serial can be passed to --dongle
+    println!("Index  Path     Serial           Description");
+    for dongle in dongles {
+        println!(
+            "{:<6} {:<8} {:<16} {} {}",
+            format!("#{}", dongle.index),
+            dongle.bus_path,
+            dongle.serial,
+            dongle.manufacturer,
+            dongle.description
+        );
     }
+}
 
-    #[test]
-    fn test_turn_off_socket() {
-        let mut dongle = MockDongle::new();
+// Networks a command is going to talk to
+fn target_networks(registry: &Registry, command: &Commands, steps: &[Step]) -> Vec<u16> {
+    match command {
+        Commands::On(cmd) => vec![resolve_network(registry, &cmd.network)],
+        Commands::Off(cmd) => vec![resolve_network(registry, &cmd.network)],
+        Commands::Read(cmd) => vec![resolve_network(registry, &cmd.network)],
+        Commands::Scene(_) | Commands::Batch(_) => steps
+            .iter()
+            .filter_map(|step| match step {
+                Step::Switch(socket, _) | Step::Read(socket) => Some(socket.network_id),
+                Step::Sleep(_) => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// The device id of the dongle the networks were commissioned with, if they
+// share one
+fn registered_dongle(registry: &Registry, networks: &[u16]) -> Option<u64> {
+    let mut paired = networks.iter().map(|&network_id| {
+        registry
+            .networks()
+            .iter()
+            .find(|network| network.network_id == network_id)
+            .and_then(|network| network.dongle)
+    });
+    let device_id = paired.next()??;
+    paired.all(|dongle| dongle == Some(device_id)).then_some(device_id)
+}
 
-        dongle.expect_lock_network().times(1);
-        dongle.expect_select_network().with(eq(0x0010)).times(1);
-        dongle.expect_switch().with(eq(0x0010), eq(0), eq(false)).times(1);
+// The bus path of the attached dongle with this device id. Only worth finding
+// when several dongles are attached, each is booted to compare its device id.
+fn find_dongle(device_id: u64) -> Option<String> {
+    let attached = ftdi::list().ok()?;
+    if attached.len() < 2 {
+        return None;
+    }
+    let found = attached.into_iter().find(|usb| {
+        FtdiTransport::open_dongle(&usb.bus_path).is_ok_and(|transport| {
+            let mut dongle = Dongle::new(SerialConnection::with_transport(Box::new(transport)));
+            dongle.boot().is_ok_and(|info| info.device_id() == device_id)
+        })
+    })?;
+    info!("Using dongle {} ({:016x}) from the registry", found.bus_path, device_id);
+    Some(found.bus_path)
+}
 
-        let args = Hacklet {
-            debug: false,
-            command: Commands::Off(OffCommand {
-                network: "0x0010".to_string(),
-                socket: "0".to_string(),
-            }),
-        };
+fn open_local(dongle: Option<&str>) -> FtdiTransport {
+    reopen_local(dongle).unwrap_or_else(|err| {
+        match dongle {
+            Some(selector) => eprintln!("Could not open dongle {}: {}", selector, err),
+            None => eprintln!("Could not open FTDI device: {}", err),
+        }
+        process::exit(1);
+    })
+}
 
-        command();
+fn reopen_local(dongle: Option<&str>) -> io::Result<FtdiTransport> {
+    match dongle {
+        Some(selector) => FtdiTransport::open_dongle(selector),
+        None => FtdiTransport::open(),
     }
+}
 
-    #[test]
-    fn test_read_socket() {
-        let mut dongle = MockDongle::new();
+fn open_replay(path: &Path) -> ReplayTransport {
+    ReplayTransport::open(path).unwrap_or_else(|err| {
+        eprintln!("Could not load capture {}: {}", path.display(), err);
+        process::exit(1);
+    })
+}
+
+fn connect_device(device: &str) -> TcpTransport {
+    let address = tcp::parse_device(device).unwrap_or_else(|| {
+        eprintln!("Unsupported device '{}', expected tcp://host:port", device);
+        process::exit(1);
+    });
+    TcpTransport::connect(address).unwrap_or_else(|err| {
+        eprintln!("Could not connect to {}: {}", device, err);
+        process::exit(1);
+    })
+}
+
+// Reads a batch script from a file, or stdin when no file (or "-") is given
+fn read_script(path: Option<&str>) -> io::Result<String> {
+    match path {
+        Some(path) if path != "-" => std::fs::read_to_string(path),
+        _ => io::read_to_string(io::stdin()),
+    }
+}
+
+fn resolve_network(registry: &Registry, network: &str) -> u16 {
+    registry.network(network).unwrap_or_else(|| {
+        eprintln!("Unknown network '{}'", network);
+        process::exit(1);
+    })
+}
+
+fn save_registry(registry: &Registry) {
+    if let Err(err) = registry.save() {
+        eprintln!("Could not save device registry: {}", err);
+        process::exit(1);
+    }
+}
+
+fn print_commissioned(devices: &[BroadcastResponse]) {
+    if devices.is_empty() {
+        println!("No devices found");
+        return;
+    }
 
-        dongle.expect_lock_network().times(1);
-        dongle.expect_select_network().with(eq(0x0010)).times(1);
-        dongle.expect_request_samples().with(eq(0x0010), eq(1)).times(1);
+    println!("{:<20} {:<8}", "DEVICE", "NETWORK");
+    for device in devices {
+        println!("{:<20} {:<8}", format!("0x{:016x}", device.device_id), format!("0x{:04x}", device.network_id));
+    }
+    println!("{} device(s) commissioned", devices.len());
+}
 
-        let args = Hacklet {
-            debug: false,
-            command: Commands::Read(ReadCommand {
-                network: "0x0010".to_string(),
-                socket: "1".to_string(),
-            }),
-        };
+#[cfg(test)]
+mod tests {
+    use crate::command::*;
 
-        command();
+    fn parse(args: &[&str]) -> Hacklet {
+        Hacklet::from_args(&["hacklet"], args).unwrap_or_else(|err| panic!("{}", err.output))
     }
 
     #[test]
-    fn test_commission_device() {
-        let mut dongle = MockDongle::new();
+    fn test_turn_on_socket() {
+        match parse(&["on", "-n", "0x0010", "-s", "1"]).command {
+            Commands::On(cmd) => assert_eq!((cmd.network.as_str(), cmd.socket.as_str()), ("0x0010", "1")),
+            _ => panic!("expected the on command"),
+        }
+    }
 
-        dongle.expect_commission().times(1);
+    #[test]
+    fn test_turn_off_socket() {
+        match parse(&["off", "--network", "office", "--socket", "0"]).command {
+            Commands::Off(cmd) => assert_eq!((cmd.network.as_str(), cmd.socket.as_str()), ("office", "0")),
+            _ => panic!("expected the off command"),
+        }
+    }
 
-        let args = Hacklet {
-            debug: false,
-            command: Commands::Commission(CommissionCommand {}),
-        };
+    #[test]
+    fn test_read_socket() {
+        let args = parse(&["-d", "read", "-n", "0x0010", "-s", "1"]);
+        assert!(args.debug);
+        assert!(matches!(args.command, Commands::Read(_)));
+    }
 
-        command();
+    #[test]
+    fn test_commission_device() {
+        match parse(&["commission"]).command {
+            Commands::Commission(cmd) => {
+                assert_eq!(cmd.count, None);
+                assert!(!cmd.until_timeout);
+                assert_eq!(cmd.timeout, 30);
+            }
+            _ => panic!("expected the commission command"),
+        }
     }
 }
@@ -0,0 +1,326 @@
+use std::fmt;
+
+use crate::{
+    capture::{Capture, Direction},
+    messages::{frame::Frame, requests::*, responses::*, to_hex, Message},
+};
+
+// Protocol dissector - splits byte streams into frames and names them after
+// the request and response types in `messages`.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decoded {
+    Frame(Frame),
+    // Bytes that could not be the start of a frame
+    Garbage(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedFrame {
+    // Microseconds since the start of the capture, if known
+    pub time: Option<u64>,
+    pub direction: Option<Direction>,
+    pub decoded: Decoded,
+}
+
+impl fmt::Display for DecodedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(time) = self.time {
+            write!(f, "[{:>6}.{:06}] ", time / 1_000_000, time % 1_000_000)?;
+        }
+        match self.direction {
+            Some(direction) => write!(f, "{} ", direction)?,
+            None => write!(f, "?? ")?,
+        }
+        match &self.decoded {
+            Decoded::Frame(frame) => {
+                write!(f, "{}", describe(frame, self.direction))?;
+                if !frame.checksum_valid() {
+                    write!(f, " BAD CHECKSUM (expected 0x{:02x})", frame.calculate_checksum())?;
+                }
+                Ok(())
+            }
+            Decoded::Garbage(bytes) => write!(f, "garbage [{}]", to_hex(bytes)),
+        }
+    }
+}
+
+// Splits a buffer into frames, returning them along with any trailing bytes
+// that do not make up a complete frame yet. A stray 0x02 would swallow the
+// frames after it, up to wherever its length byte points. So when a valid
+// frame starts inside a frame that is bad or runs past the buffer, the 0x02 is
+// taken as garbage and the split resyncs on the next one.
+pub fn split_frames(mut input: &[u8]) -> (Vec<Decoded>, &[u8]) {
+    let mut decoded = Vec::new();
+    let mut garbage = Vec::new();
+
+    while !input.is_empty() {
+        match Frame::read(input) {
+            Ok((rest, frame)) if frame.checksum_valid() || !frame_starts_within(input, input.len() - rest.len()) => {
+                if !garbage.is_empty() {
+                    decoded.push(Decoded::Garbage(std::mem::take(&mut garbage)));
+                }
+                decoded.push(Decoded::Frame(frame));
+                input = rest;
+            }
+            Err(nom::Err::Incomplete(_)) if !frame_starts_within(input, input.len()) => break,
+            _ => {
+                garbage.push(input[0]);
+                input = &input[1..];
+            }
+        }
+    }
+
+    if !garbage.is_empty() {
+        decoded.push(Decoded::Garbage(garbage));
+    }
+    (decoded, input)
+}
+
+// True if a complete frame with a valid checksum starts after the first byte
+// of `input` and before `end`
+fn frame_starts_within(input: &[u8], end: usize) -> bool {
+    (1..end)
+        .filter(|&start| input[start] == 0x02)
+        .any(|start| matches!(Frame::read(&input[start..]), Ok((_, frame)) if frame.checksum_valid()))
+}
+
+// Decodes a byte stream of unknown direction, e.g. pasted from a debug log
+pub fn decode_bytes(bytes: &[u8]) -> Vec<DecodedFrame> {
+    let (decoded, rest) = split_frames(bytes);
+    let mut frames: Vec<DecodedFrame> = decoded
+        .into_iter()
+        .map(|decoded| DecodedFrame { time: None, direction: None, decoded })
+        .collect();
+    if !rest.is_empty() {
+        frames.push(DecodedFrame {
+            time: None,
+            direction: None,
+            decoded: Decoded::Garbage(rest.to_vec()),
+        });
+    }
+    frames
+}
+
+// Reassembles the chunks of a capture into frames, each direction separately.
+// A frame is stamped with the time of the chunk that completed it.
+pub fn decode_capture(capture: &Capture) -> Vec<DecodedFrame> {
+    let mut frames = Vec::new();
+    let mut tx = Vec::new();
+    let mut rx = Vec::new();
+
+    for event in &capture.events {
+        let buffer = match event.direction {
+            Direction::Tx => &mut tx,
+            Direction::Rx => &mut rx,
+        };
+        buffer.extend_from_slice(&event.data);
+
+        let (decoded, rest) = split_frames(buffer);
+        let consumed = buffer.len() - rest.len();
+        frames.extend(decoded.into_iter().map(|decoded| DecodedFrame {
+            time: Some(event.time),
+            direction: Some(event.direction),
+            decoded,
+        }));
+        buffer.drain(..consumed);
+    }
+
+    for (direction, rest) in [(Direction::Tx, tx), (Direction::Rx, rx)] {
+        if !rest.is_empty() {
+            frames.push(DecodedFrame {
+                time: capture.events.last().map(|event| event.time),
+                direction: Some(direction),
+                decoded: Decoded::Garbage(rest),
+            });
+        }
+    }
+    frames
+}
+
+// Names a frame after the message type its command code belongs to and prints
+// its fields. Several commands are shared by a request and its acknowledgement,
+// without a direction those are told apart by the ack's one byte payload.
+// Anything that is not a request is parsed as a `Response`.
+pub fn describe(frame: &Frame, direction: Option<Direction>) -> String {
+    let bytes = frame.as_bytes();
+    let is_request = match direction {
+        Some(direction) => direction == Direction::Tx,
+        None => frame.payload.len() != 1,
+    };
+
+    match (frame.command, is_request) {
+        (0x4004, _) => show::<BootRequest>(&bytes),
+        (0x4000, _) => show::<BootConfirmRequest>(&bytes),
+        (0xA236, _) if frame.payload == UnlockRequest::new().data.to_be_bytes() => show::<UnlockRequest>(&bytes),
+        (0xA236, _) => show::<LockRequest>(&bytes),
+        (0x4022, true) => show::<UpdateTimeRequest>(&bytes),
+        (0x4003, true) => show::<HandshakeRequest>(&bytes),
+        (0x4024, true) => show::<SamplesRequest>(&bytes),
+        (0x4023, true) => show::<ScheduleRequest>(&bytes),
+        _ => match Response::read(&bytes) {
+            Ok((_, response)) => describe_response(&response),
+            Err(_) => format!("Malformed {}", describe_payload(frame.command, &frame.payload)),
+        },
+    }
+}
+
+fn describe_response(response: &Response) -> String {
+    match response {
+        Response::Boot(response) => compact_debug(response),
+        Response::BootConfirm(response) => compact_debug(response),
+        Response::Broadcast(response) => compact_debug(response),
+        Response::Lock(response) => compact_debug(response),
+        Response::UpdateTimeAck(response) => compact_debug(response),
+        Response::UpdateTime(response) => compact_debug(response),
+        Response::Handshake(response) => compact_debug(response),
+        Response::Ack(response) => compact_debug(response),
+        Response::Samples(response) => compact_debug(response),
+        Response::Schedule(response) => compact_debug(response),
+        Response::Unknown { command, payload } => format!("Unknown {}", describe_payload(*command, payload)),
+    }
+}
+
+fn describe_payload(command: u16, payload: &[u8]) -> String {
+    format!("{{ command: 0x{:04x}, payload_length: {}, payload: [{}] }}", command, payload.len(), to_hex(payload))
+}
+
+fn show<M: Message + fmt::Debug>(bytes: &[u8]) -> String {
+    match M::read(bytes) {
+        Ok((_, message)) => compact_debug(&message),
+        Err(_) => format!("{} (malformed) [{}]", std::any::type_name::<M>().rsplit("::").next().unwrap_or("?"), to_hex(bytes)),
+    }
+}
+
+// `{:#x?}` gives field names and hex values but spreads them over many lines
+fn compact_debug<T: fmt::Debug>(value: &T) -> String {
+    let pretty = format!("{:#x?}", value);
+    pretty
+        .lines()
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace(", }", " }")
+        .replace(", ]", " ]")
+        .replace("[ ", "[")
+        .replace(" ]", "]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::CaptureEvent;
+
+    #[test]
+    fn names_known_frames_and_shows_unknown_ones() {
+        let mut bytes = LockResponse::new().as_bytes();
+        bytes.push(0xff);
+        bytes.extend(Frame::new(0x1234, vec![0xab]).as_bytes());
+
+        let lines: Vec<String> = decode_bytes(&bytes).iter().map(|frame| frame.to_string()).collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("?? LockResponse { header: 0x2, command: 0xa0f9,"), "{}", lines[0]);
+        assert_eq!(lines[1], "?? garbage [ff]");
+        assert_eq!(lines[2], "?? Unknown { command: 0x1234, payload_length: 1, payload: [ab] }");
+    }
+
+    #[test]
+    fn uses_the_direction_to_tell_requests_from_acks() {
+        let request = Frame::new(0x4024, SamplesRequest::new(0x1234, 1).as_bytes()[4..10].to_vec());
+        let ack = Frame::new(0x4024, vec![0x00]);
+        assert!(describe(&request, Some(Direction::Tx)).starts_with("SamplesRequest"));
+        assert!(describe(&ack, Some(Direction::Rx)).starts_with("AckResponse"));
+        assert!(describe(&ack, None).starts_with("AckResponse"));
+    }
+
+    #[test]
+    fn resyncs_after_a_stray_header_byte() {
+        // A short bogus frame overlapping the next one, and one whose length
+        // runs past the end of the buffer
+        for garbage in [vec![0x02, 0x40, 0xff], vec![0x02, 0x40, 0xff, 0xf0]] {
+            let mut bytes = garbage.clone();
+            bytes.extend(LockResponse::new().as_bytes());
+            bytes.extend(HandshakeResponse::new().as_bytes());
+            let truncated = &AckResponse::new().as_bytes()[..4];
+            bytes.extend(truncated);
+
+            let (decoded, rest) = split_frames(&bytes);
+            assert_eq!(decoded.len(), 3, "{:?}", decoded);
+            assert_eq!(decoded[0], Decoded::Garbage(garbage));
+            assert!(matches!(&decoded[1], Decoded::Frame(frame) if frame.command == 0xA0F9));
+            assert!(matches!(&decoded[2], Decoded::Frame(frame) if frame.command == 0x4003));
+            // Only the end of the buffer is waited on
+            assert_eq!(rest, truncated);
+        }
+    }
+
+    #[test]
+    fn flags_bad_checksums() {
+        let frames = decode_bytes(&[0x02, 0x40, 0x80, 0x01, 0x10, 0x01]);
+        assert!(frames[0].to_string().ends_with("BAD CHECKSUM (expected 0xd1)"), "{}", frames[0]);
+    }
+
+    #[test]
+    fn decodes_a_boot_lock_and_handshake_session() {
+        let chunks = vec![
+            (Direction::Tx, BootRequest::new().as_bytes()),
+            (Direction::Rx, BootResponse::new((1..=12).collect(), 0x0123_4567_89ab_cdef, 0x0506).as_bytes()),
+            (Direction::Tx, BootConfirmRequest::new().as_bytes()),
+            (Direction::Rx, BootConfirmResponse::new().as_bytes()),
+            (Direction::Tx, LockRequest::new().as_bytes()),
+            (Direction::Rx, AckResponse::new().as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+            (Direction::Tx, HandshakeRequest::new(0x1234).as_bytes()),
+            (Direction::Rx, HandshakeResponse::new().as_bytes()),
+        ];
+        let capture = Capture {
+            start: 0,
+            events: chunks
+                .into_iter()
+                .enumerate()
+                .map(|(i, (direction, data))| CaptureEvent { time: i as u64, direction, data })
+                .collect(),
+        };
+
+        let names: Vec<String> = decode_capture(&capture)
+            .iter()
+            .map(|frame| {
+                let line = frame.to_string();
+                assert!(!line.contains("garbage") && !line.contains("BAD CHECKSUM"), "{}", line);
+                line.split_whitespace().nth(3).unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "BootRequest",
+                "BootResponse",
+                "BootConfirmRequest",
+                "BootConfirmResponse",
+                "LockRequest",
+                "AckResponse",
+                "LockResponse",
+                "HandshakeRequest",
+                "HandshakeResponse",
+            ]
+        );
+    }
+
+    #[test]
+    fn reassembles_frames_split_across_chunks() {
+        let response = BootConfirmResponse::new().as_bytes();
+        let capture = Capture {
+            start: 0,
+            events: vec![
+                CaptureEvent { time: 10, direction: Direction::Tx, data: BootRequest::new().as_bytes() },
+                CaptureEvent { time: 20, direction: Direction::Rx, data: response[..2].to_vec() },
+                CaptureEvent { time: 1_500_030, direction: Direction::Rx, data: response[2..].to_vec() },
+            ],
+        };
+
+        let frames = decode_capture(&capture);
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].to_string().starts_with("[     0.000010] tx BootRequest"), "{}", frames[0]);
+        assert!(frames[1].to_string().starts_with("[     1.500030] rx BootConfirmResponse"), "{}", frames[1]);
+    }
+}
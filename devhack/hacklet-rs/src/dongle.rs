@@ -1,282 +1,755 @@
-use std::time::{Duration, Instant};
+use std::{
+    io,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
-use log::{self, info};
+use log::{self, info, warn};
 
 use crate::{
-    messages::{requests::*, responses::*, Message},
+    messages::{requests::*, responses::*, Message, MAX_FRAME_LEN},
     serial_connection::*,
 };
 
+// How long to wait for the dongle to answer a request
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often long running operations check whether they were interrupted
+const INTERRUPT_POLL: Duration = Duration::from_millis(500);
+
+// How often `reconnect` tries to open the dongle again, and the delay before
+// the second attempt, doubled after every failure
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(250);
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DongleError {
+    // The dongle did not answer within the response timeout
+    Timeout,
+    // The dongle answered with a frame we did not expect
+    InvalidResponse(Vec<u8>),
+    // The connection to the dongle failed
+    Io(std::io::Error),
+    // The dongle is no longer available, e.g. its worker thread has stopped
+    Disconnected,
+    // The interrupt flag was set while waiting for the dongle
+    Interrupted,
+}
+
+impl std::fmt::Display for DongleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DongleError::Timeout => write!(f, "no response from dongle"),
+            DongleError::InvalidResponse(bytes) => write!(f, "unexpected response {:02x?}", bytes),
+            DongleError::Io(err) => write!(f, "connection to dongle failed: {}", err),
+            DongleError::Disconnected => write!(f, "dongle disconnected"),
+            DongleError::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+impl std::error::Error for DongleError {}
+
+// What the dongle reports about itself when it boots
+#[derive(Debug, Clone, PartialEq)]
+pub struct DongleInfo {
+    // Unique to each dongle, used to tell them apart in logs and the registry
+    device_id: u64,
+    // The rest of the boot response. What these mean (firmware version or
+    // otherwise) is not known yet, they are kept so they can be compared
+    // between dongles.
+    data: Vec<u8>,
+    data2: u16,
+}
+
+impl DongleInfo {
+    pub(crate) fn from_boot(response: &BootResponse) -> Self {
+        DongleInfo {
+            device_id: response.device_id,
+            data: response.data.clone(),
+            data2: response.data2,
+        }
+    }
+
+    pub fn device_id(&self) -> u64 {
+        self.device_id
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data2(&self) -> u16 {
+        self.data2
+    }
+}
+
+impl std::fmt::Display for DongleInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.device_id)
+    }
+}
+
+// Opens a new connection to the dongle, see `Dongle::set_connect`
+type Connect = Box<dyn FnMut() -> io::Result<SerialConnection>>;
+
 pub struct Dongle {
     serial: SerialConnection,
+    interrupted: Arc<AtomicBool>,
+    selected_network: Option<u16>,
+    info: Option<DongleInfo>,
+    connect: Option<Connect>,
+}
+
+// Returned by `Dongle::unlock_network`. The network stays open for pairing
+// while the guard is alive and is locked again when it is dropped, whether that
+// happens normally, on an early return or while unwinding from a panic.
+#[must_use = "the network is locked again as soon as the guard is dropped"]
+pub struct UnlockedNetwork<'a> {
+    dongle: &'a mut Dongle,
+    relock: bool,
+}
+
+impl Deref for UnlockedNetwork<'_> {
+    type Target = Dongle;
+
+    fn deref(&self) -> &Dongle {
+        self.dongle
+    }
+}
+
+impl DerefMut for UnlockedNetwork<'_> {
+    fn deref_mut(&mut self) -> &mut Dongle {
+        self.dongle
+    }
+}
+
+impl UnlockedNetwork<'_> {
+    // Leaves the network unlocked after the guard goes away, the caller is
+    // then responsible for calling `Dongle::lock_network` on every way out,
+    // panics included
+    pub fn keep_unlocked(mut self) {
+        self.relock = false;
+    }
+}
+
+impl Drop for UnlockedNetwork<'_> {
+    fn drop(&mut self) {
+        if !self.relock {
+            return;
+        }
+        if std::thread::panicking() {
+            warn!("Relocking network after a failure");
+        }
+        if let Err(err) = self.dongle.lock_network() {
+            warn!("Could not relock network: {}", err);
+        }
+    }
+}
+
+impl From<std::io::Error> for DongleError {
+    fn from(err: std::io::Error) -> Self {
+        DongleError::Io(err)
+    }
+}
+
+impl DongleError {
+    // True if the dongle itself went away (unplugged, USB reset, bridge gone)
+    // rather than a single request going wrong. Other I/O errors, such as a
+    // replay diverging, won't go away by reconnecting.
+    pub fn is_connection_lost(&self) -> bool {
+        match self {
+            DongleError::Io(err) => matches!(
+                err.kind(),
+                io::ErrorKind::NotConnected
+                    | io::ErrorKind::NotFound
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::UnexpectedEof
+            ),
+            DongleError::Disconnected => true,
+            _ => false,
+        }
+    }
+}
+
+// What `commission` found. `failed` holds the networks whose clock could not
+// be set, their devices are still in `devices`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct Commissioned {
+    pub devices: Vec<BroadcastResponse>,
+    pub failed: Vec<(u16, DongleError)>,
 }
 
 impl Dongle {
-    // Open method - Initializes and yields a dongle instance
-    pub fn open<F>(callback: F)
+    // Open method - Initializes and yields a dongle instance, returns what the
+    // dongle reported when it booted
+    #[cfg(feature = "ftdi")]
+    pub fn open<F>(callback: F) -> Result<DongleInfo, DongleError>
     where
-        F: FnOnce(&mut Dongle) -> ()
+        F: FnOnce(&mut Dongle)
     {
-        let serial = SerialConnection::new();
-        let mut dongle = Dongle { serial };
+        Dongle::open_with(SerialConnection::new()?, callback)
+    }
 
-        dongle.boot();
-        dongle.boot_confirm();
+    // Like open, but over an already configured connection
+    pub fn open_with<F>(serial: SerialConnection, callback: F) -> Result<DongleInfo, DongleError>
+    where
+        F: FnOnce(&mut Dongle)
+    {
+        let mut dongle = Dongle::new(serial);
+
+        let info = dongle.boot()?;
         callback(&mut dongle);
 
         // Serial connection is closed at the end (Drop implemented in Rust can handle this)
+        Ok(info)
     }
 
     pub fn new(serial: SerialConnection) -> Self {
-        Dongle { serial }
+        Dongle {
+            serial,
+            interrupted: Arc::new(AtomicBool::new(false)),
+            selected_network: None,
+            info: None,
+            connect: None,
+        }
+    }
+
+    // How to open the connection again once it is lost, without it
+    // `reconnect` fails straight away
+    pub fn set_connect<F>(&mut self, connect: F)
+    where
+        F: FnMut() -> io::Result<SerialConnection> + 'static
+    {
+        self.connect = Some(Box::new(connect));
+    }
+
+    pub fn can_reconnect(&self) -> bool {
+        self.connect.is_some()
+    }
+
+    // Opens the connection again after it was lost and boots the dongle,
+    // waiting longer after every failed attempt. A capture being recorded
+    // carries on over the new connection, the selected network does not.
+    pub fn reconnect(&mut self) -> Result<DongleInfo, DongleError> {
+        let mut connect = self.connect.take().ok_or(DongleError::Disconnected)?;
+        let result = self.reconnect_with(&mut connect);
+        self.connect = Some(connect);
+        result
+    }
+
+    // Runs `operation`, and if the connection is lost on the way reconnects
+    // and runs it once more. Only for operations that are safe to repeat.
+    pub fn retrying<T, F>(&mut self, mut operation: F) -> Result<T, DongleError>
+    where
+        F: FnMut(&mut Dongle) -> Result<T, DongleError>
+    {
+        match operation(self) {
+            Err(err) if err.is_connection_lost() && self.can_reconnect() => {
+                warn!("Lost the dongle, reconnecting: {}", err);
+                self.reconnect()?;
+                operation(self)
+            }
+            result => result,
+        }
     }
 
-    // Commission method - listens for new devices on the network
-    pub fn commission(&mut self) {
-        let mut response: Option<BroadcastResponse> = None;
-        self.unlock_network();
+    // What the dongle reported the last time it booted
+    pub fn info(&self) -> Option<&DongleInfo> {
+        self.info.as_ref()
+    }
+
+    // Flag that long running operations such as `commission` poll, setting it
+    // (e.g. from a Ctrl-C handler) makes them stop early and clean up
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupted)
+    }
 
-        let timeout = Duration::from_secs(30);
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
+    }
+
+    // Sleeps for `duration`, or until interrupted
+    pub fn wait(&self, duration: Duration) -> Result<(), DongleError> {
         let start_time = Instant::now();
+        while let Some(remaining) = duration.checked_sub(start_time.elapsed()) {
+            if self.is_interrupted() {
+                return Err(DongleError::Interrupted);
+            }
+            sleep(remaining.min(INTERRUPT_POLL));
+        }
+        Ok(())
+    }
+
+    // Commission method - listens for new devices on the network until `count`
+    // devices have been found (or forever when `None`) or the timeout expires.
+    // Setting the time afterwards is done per network, a network that fails
+    // still leaves its devices in the result.
+    pub fn commission(&mut self, count: Option<usize>, timeout: Duration) -> Result<Commissioned, DongleError> {
+        let mut found: Vec<BroadcastResponse> = Vec::new();
+        let mut network = self.unlock_network()?;
+
+        let start_time = Instant::now();
+        info!("Listening for devices ...");
 
-        while start_time.elapsed() < timeout {
-            info!("Listening for devices ...");
-            let mut buffer = self.serial.receive(4);
-            buffer.extend(self.serial.receive(buffer[3] as usize + 1));
-
-            if buffer[1] == 0xa0 {
-                response = Some(BroadcastResponse::read(&buffer).unwrap().1);
-                let resp = response.as_ref().unwrap();
-                info!("{}",
-                    &format!("Found device 0x{:x} on network 0x{:x}", resp.device_id, resp.network_id)
-                );
+        while count.is_none_or(|count| found.len() < count) {
+            if network.is_interrupted() {
+                warn!("Commissioning interrupted");
                 break;
             }
+            let remaining = match timeout.checked_sub(start_time.elapsed()) {
+                Some(remaining) => remaining,
+                None => break,
+            };
+            let mut buffer = match network.serial.receive_timeout(4, remaining.min(INTERRUPT_POLL))? {
+                Some(buffer) => buffer,
+                None => continue,
+            };
+            match network.serial.receive_timeout(buffer[3] as usize + 1, RESPONSE_TIMEOUT)? {
+                Some(rest) => buffer.extend(rest),
+                None => {
+                    warn!("Truncated frame {:02x?}", buffer);
+                    continue;
+                }
+            }
+
+            if buffer[1] != 0xa0 {
+                continue;
+            }
+
+            match BroadcastResponse::read(&buffer) {
+                Ok((_, resp)) => {
+                    // Devices keep broadcasting while they pair, only report each once
+                    if found.iter().any(|f| f.device_id == resp.device_id) {
+                        continue;
+                    }
+                    info!("Found device 0x{:x} on network 0x{:x}", resp.device_id, resp.network_id);
+                    found.push(resp);
+                }
+                Err(err) => warn!("Ignoring malformed broadcast {:?}: {:?}", buffer, err),
+            }
         }
 
-        if let Some(resp) = response {
-            self.update_time(resp.network_id);
+        let mut networks: Vec<u16> = found.iter().map(|resp| resp.network_id).collect();
+        networks.sort_unstable();
+        networks.dedup();
+        let mut failed = Vec::new();
+        for network_id in networks {
+            if let Err(err) = network.update_time(network_id) {
+                warn!("Could not set the time on network 0x{:x}: {}", network_id, err);
+                failed.push((network_id, err));
+            }
         }
-        self.lock_network();
+
+        Ok(Commissioned { devices: found, failed })
     }
 
     // Selects the network
-    pub fn select_network(&mut self, network_id: u16) {
-        self.serial.transmit(&HandshakeRequest::new(network_id).as_bytes());
-        let _ = HandshakeResponse::read(&self.serial.receive(6));
+    pub fn select_network(&mut self, network_id: u16) -> Result<(), DongleError> {
+        self.send(&HandshakeRequest::new(network_id))?;
+        let _ = HandshakeResponse::read(&self.receive(6)?);
+        self.selected_network = Some(network_id);
+        Ok(())
+    }
+
+    // Selects the network unless it is already the selected one, so a run of
+    // commands against the same network only pays for one handshake
+    pub fn ensure_network(&mut self, network_id: u16) -> Result<(), DongleError> {
+        if self.selected_network != Some(network_id) {
+            self.select_network(network_id)?;
+        }
+        Ok(())
     }
 
     // Request samples
-    pub fn request_samples(&mut self, network_id: u16, channel_id: u16) -> SamplesResponse {
+    pub fn request_samples(&mut self, network_id: u16, channel_id: u16) -> Result<SamplesResponse, DongleError> {
         info!("Requesting samples");
-        self.serial.transmit(&SamplesRequest::new(network_id, channel_id).as_bytes());
-        let _ = AckResponse::read(&self.serial.receive(6));
+        self.send(&SamplesRequest::new(network_id, channel_id))?;
+        let _ = AckResponse::read(&self.receive(6)?);
 
-        let mut buffer = self.serial.receive(4);
+        let mut buffer = self.receive(4)?;
         let remaining_bytes = buffer[3] as usize + 1;
-        buffer.extend(self.serial.receive(remaining_bytes));
+        buffer.extend(self.receive(remaining_bytes)?);
 
-        let response = SamplesResponse::read(&buffer).unwrap().1;
+        let response = match SamplesResponse::read(&buffer) {
+            Ok((_, response)) => response,
+            Err(_) => return Err(DongleError::InvalidResponse(buffer)),
+        };
 
         for sample in response.samples.iter() {
             let (time, wattage) = ((*sample >> 8) as u8, (*sample & 0xFF) as u8);
-            info!("{}", format!("{}w at {}", wattage, time));
+            info!("{}w at {}", wattage, time);
         }
 
-        info!("{}", format!(
+        info!(
             "{} returned, {} remaining",
             response.sample_count, response.stored_sample_count
-        ));
+        );
 
-        response
+        Ok(response)
     }
 
-    // Switch a socket on or off
-    pub fn switch(&mut self, network_id: u16, channel_id: u16, state: bool) {
-        let mut request = ScheduleRequest::new(network_id, channel_id);
+    // Switch a socket on or off, succeeds once the dongle acknowledges the new schedule
+    pub fn switch(&mut self, network_id: u16, channel_id: u16, state: bool) -> Result<(), DongleError> {
+        // Schedule requests carry the channel in a single byte
+        let mut request = ScheduleRequest::new(network_id, channel_id as u8);
 
         if state {
             request.always_on();
-            info!("{}", format!(
+            info!(
                 "Turning on channel {} on network 0x{:x}", channel_id, network_id
-            ));
+            );
         } else {
             request.always_off();
-            info!("{}", format!(
+            info!(
                 "Turning off channel {} on network 0x{:x}", channel_id, network_id
-            ));
+            );
         }
 
-        self.serial.transmit(&request.as_bytes());
-        let _ = ScheduleResponse::read(&self.serial.receive(6));
+        self.send(&request)?;
+        let buffer = self.receive(6)?;
+        match ScheduleResponse::read(&buffer) {
+            Ok((_, response)) if response.command() == 0x4023 && response.checksum() == response.calculate_checksum() => Ok(()),
+            _ => Err(DongleError::InvalidResponse(buffer)),
+        }
     }
 
-    // Unlock the network
-    pub fn unlock_network(&mut self) {
+    // Sends bytes as-is and collects whatever frames come back within the timeout
+    pub fn raw(&mut self, bytes: &[u8], timeout: Duration) -> Result<Vec<Vec<u8>>, DongleError> {
+        self.serial.transmit(bytes)?;
+        // A raw frame may well have been a handshake
+        self.selected_network = None;
+
+        let start_time = Instant::now();
+        let mut frames = Vec::new();
+        while let Some(remaining) = timeout.checked_sub(start_time.elapsed()) {
+            if self.is_interrupted() {
+                break;
+            }
+            let mut frame = match self.serial.receive_timeout(4, remaining.min(INTERRUPT_POLL))? {
+                Some(frame) => frame,
+                None => continue,
+            };
+            match self.serial.receive_timeout(frame[3] as usize + 1, RESPONSE_TIMEOUT)? {
+                Some(rest) => frame.extend(rest),
+                None => warn!("Truncated frame {:02x?}", frame),
+            }
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    // Unlock the network, it is locked again when the returned guard is dropped
+    pub fn unlock_network(&mut self) -> Result<UnlockedNetwork<'_>, DongleError> {
         info!("Unlocking network");
-        self.serial.transmit(&UnlockRequest::new().as_bytes());
-        let _ = LockResponse::read(&self.serial.receive(6));
+        self.send(&UnlockRequest::new())?;
+        self.selected_network = None;
+
+        // Guard first, so a failure while waiting for the ack still relocks
+        let network = UnlockedNetwork { dongle: self, relock: true };
+        let buffer = network.dongle.receive(6)?;
+        let _ = LockResponse::read(&buffer);
         info!("Unlocking complete");
+        Ok(network)
     }
 
-    // Lock the network
-    pub fn lock_network(&mut self) {
+    // Lock the network. This is also how an interrupted command cleans up, so
+    // it waits for the ack even when the interrupt flag is set.
+    pub fn lock_network(&mut self) -> Result<(), DongleError> {
         info!("Locking network");
-        self.serial.transmit(&LockRequest::new().as_bytes());
-        let _ = LockResponse::read(&self.serial.receive(6));
-        info!("Locking complete");
+        self.send(&LockRequest::new())?;
+        match self.serial.receive_timeout(6, RESPONSE_TIMEOUT)? {
+            Some(buffer) => {
+                let _ = LockResponse::read(&buffer);
+                info!("Locking complete");
+            }
+            None => warn!("Dongle did not acknowledge the lock request"),
+        }
+        Ok(())
     }
 
-    // Boot the dongle
-    fn boot(&mut self) {
+    // Boot the dongle and confirm boot success. Also needed after the dongle
+    // has been reconnected.
+    pub fn boot(&mut self) -> Result<DongleInfo, DongleError> {
         info!("Booting");
-        self.serial.transmit(&BootRequest::new().as_bytes());
-        let _ = BootResponse::read(&self.serial.receive(27));
+        self.selected_network = None;
+        self.send(&BootRequest::new())?;
+        let buffer = self.receive(27)?;
+        let info = match BootResponse::read(&buffer) {
+            Ok((_, response)) if response.command() == 0x4084 => DongleInfo::from_boot(&response),
+            _ => return Err(DongleError::InvalidResponse(buffer)),
+        };
+
+        self.send(&BootConfirmRequest::new())?;
+        let _ = BootConfirmResponse::read(&self.receive(6)?);
+        info!("Booted dongle {}", info);
+        self.info = Some(info.clone());
+        Ok(info)
+    }
+
+    fn reconnect_with(&mut self, connect: &mut Connect) -> Result<DongleInfo, DongleError> {
+        let mut backoff = RECONNECT_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let err = match connect() {
+                Ok(mut serial) => {
+                    serial.keep_recording(&mut self.serial);
+                    self.serial = serial;
+                    match self.boot() {
+                        Ok(info) => return Ok(info),
+                        Err(err) => err,
+                    }
+                }
+                Err(err) => err.into(),
+            };
+            if attempt >= RECONNECT_ATTEMPTS {
+                return Err(err);
+            }
+            warn!("Could not reopen dongle, retrying in {:?}: {}", backoff, err);
+            self.wait(backoff)?;
+            backoff *= 2;
+            attempt += 1;
+        }
     }
 
-    // Confirm boot success
-    fn boot_confirm(&mut self) {
-        self.serial.transmit(&BootConfirmRequest::new().as_bytes());
-        let _ = BootConfirmResponse::read(&self.serial.receive(6));
-        info!("Booting complete");
+    // Update device time. Only run once listening has stopped, so it waits
+    // for the dongle even after an interrupt.
+    fn update_time(&mut self, network_id: u16) -> Result<(), DongleError> {
+        self.send(&UpdateTimeRequest::now(network_id))?;
+        let buffer = self.serial.receive_timeout(6, RESPONSE_TIMEOUT)?.ok_or(DongleError::Timeout)?;
+        let _ = UpdateTimeAckResponse::read(&buffer);
+        let buffer = self.serial.receive_timeout(8, RESPONSE_TIMEOUT)?.ok_or(DongleError::Timeout)?;
+        let _ = UpdateTimeResponse::read(&buffer);
+        Ok(())
     }
 
-    // Update device time
-    fn update_time(&mut self, network_id: u16) {
-        self.serial.transmit(&UpdateTimeRequest::new(network_id).as_bytes());
-        let _ = UpdateTimeAckResponse::read(&self.serial.receive(6));
-        let _ = UpdateTimeResponse::read(&self.serial.receive(8));
+    // Waits for the reply to a request, giving up after RESPONSE_TIMEOUT or
+    // as soon as the interrupt flag is set
+    fn receive(&mut self, bytes: usize) -> Result<Vec<u8>, DongleError> {
+        let start_time = Instant::now();
+        loop {
+            if self.is_interrupted() {
+                return Err(DongleError::Interrupted);
+            }
+            let remaining = RESPONSE_TIMEOUT.checked_sub(start_time.elapsed()).ok_or(DongleError::Timeout)?;
+            if let Some(buffer) = self.serial.receive_timeout(bytes, remaining.min(INTERRUPT_POLL))? {
+                return Ok(buffer);
+            }
+        }
+    }
+
+    // Encodes on the stack, so polling does not allocate per request
+    fn send<M: Message>(&mut self, message: &M) -> std::io::Result<()> {
+        let mut buffer = [0; MAX_FRAME_LEN];
+        let len = message.encode_into(&mut buffer);
+        self.serial.transmit(&buffer[..len])
     }
 }
 
+// Regressions recorded from real sessions, played back with `ReplayTransport`
 #[cfg(test)]
-mod tests {
-    use mockall::{automock, predicate::*};
+mod replay_tests {
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use crate::{
+        capture::Direction,
+        dongle::{Dongle, DongleError},
+        messages::{requests::*, responses::*},
+        replay::{ReplayTransport, SharedReplay},
+        serial_connection::SerialConnection,
+        transport::Transport,
+    };
+
+    // Sets the interrupt flag the first time nothing arrives, i.e. once the
+    // dongle is left listening
+    struct InterruptWhenQuiet(SharedReplay, Arc<AtomicBool>);
+
+    impl Transport for InterruptWhenQuiet {
+        fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            self.0.write(data)
+        }
 
-    #[automock]
-    pub trait SerialPortTrait {
-        fn write_data(&mut self, data: &[u8]) -> Result<usize, std::io::Error>;
-        fn read_data(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error>;
-        fn usb_close(&mut self);
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.0.read(buf)?;
+            if len == 0 {
+                self.1.store(true, Ordering::SeqCst);
+            }
+            Ok(len)
+        }
     }
 
-    pub struct HackletDongle<P: SerialPortTrait> {
-        serial_port: P,
+    // Keeps a handle on the replay so tests can check it was played to the end
+    fn replay(frames: Vec<(Direction, Vec<u8>)>) -> (Dongle, SharedReplay) {
+        let transport = ReplayTransport::from_frames(frames).into_shared();
+        (Dongle::new(transport.connection()), transport)
     }
 
-    impl<P: SerialPortTrait> HackletDongle<P> {
-        pub fn open(serial_port: P) -> Self {
-            HackletDongle { serial_port }
-        }
+    #[test]
+    fn switch_turns_a_socket_on() {
+        let mut request = ScheduleRequest::new(0x1234, 1);
+        request.always_on();
+        let (mut dongle, transport) = replay(vec![
+            (Direction::Tx, request.as_bytes()),
+            (Direction::Rx, ScheduleResponse::new().as_bytes()),
+        ]);
+
+        dongle.switch(0x1234, 1, true).unwrap();
+        assert!(transport.is_finished());
+    }
 
-        pub async fn boot(&mut self) -> Result<(), std::io::Error> {
-            self.serial_port.write_data(&[0x02, 0x40, 0x04, 0x00, 0x44])?;
-            let mut buffer = [0u8; 32];
-            self.serial_port.read_data(&mut buffer)?;
-            // Process response here...
+    #[test]
+    fn switch_rejects_unexpected_responses() {
+        let mut request = ScheduleRequest::new(0x1234, 1);
+        request.always_off();
+        let (mut dongle, _) = replay(vec![
+            (Direction::Tx, request.as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+        ]);
 
-            Ok(())
-        }
+        assert!(matches!(dongle.switch(0x1234, 1, false), Err(DongleError::InvalidResponse(_))));
+    }
 
-        pub async fn request_sample(&mut self) -> Result<(), std::io::Error> {
-            // Implementation of request_sample...
-            Ok(())
-        }
+    #[test]
+    fn commission_finds_a_device_and_relocks() {
+        let (mut dongle, transport) = replay(vec![
+            (Direction::Tx, UnlockRequest::new().as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+            (Direction::Rx, BroadcastResponse::new(0x1234, 0xdeadbeef, 0).as_bytes()),
+            (Direction::Tx, UpdateTimeRequest::now(0x1234).as_bytes()),
+            (Direction::Rx, UpdateTimeAckResponse::new().as_bytes()),
+            (Direction::Rx, UpdateTimeResponse::new(0x1234).as_bytes()),
+            (Direction::Tx, LockRequest::new().as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+        ]);
+
+        let commissioned = dongle.commission(Some(1), Duration::from_secs(5)).unwrap();
+        let devices = &commissioned.devices;
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].device_id, 0xdeadbeef);
+        assert_eq!(devices[0].network_id, 0x1234);
+        assert!(commissioned.failed.is_empty());
+        assert!(transport.is_finished());
+    }
 
-        pub fn close(&mut self) {
-            self.serial_port.usb_close();
-        }
+    #[test]
+    fn commission_keeps_the_devices_of_a_network_that_failed() {
+        let (mut dongle, transport) = replay(vec![
+            (Direction::Tx, UnlockRequest::new().as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+            (Direction::Rx, BroadcastResponse::new(0x5678, 0xfeedface, 0).as_bytes()),
+            (Direction::Rx, BroadcastResponse::new(0x1234, 0xdeadbeef, 0).as_bytes()),
+            (Direction::Tx, UpdateTimeRequest::now(0x1234).as_bytes()),
+            (Direction::Rx, UpdateTimeAckResponse::new().as_bytes()),
+            (Direction::Rx, UpdateTimeResponse::new(0x1234).as_bytes()),
+            // The dongle goes away before it acknowledges the second network
+            (Direction::Tx, UpdateTimeRequest::now(0x5678).as_bytes()),
+        ]);
+
+        let commissioned = dongle.commission(Some(2), Duration::from_secs(5)).unwrap();
+        assert_eq!(commissioned.devices.len(), 2);
+        assert!(matches!(commissioned.failed.as_slice(), [(0x5678, DongleError::Io(_))]));
+        assert!(transport.is_finished());
+    }
+
+    #[test]
+    fn commission_relocks_when_interrupted() {
+        let (mut dongle, transport) = replay(vec![
+            (Direction::Tx, UnlockRequest::new().as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+            (Direction::Tx, LockRequest::new().as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+        ]);
+
+        let quiet = InterruptWhenQuiet(transport.clone(), dongle.interrupt_flag());
+        dongle.serial = SerialConnection::with_transport(Box::new(quiet));
+        assert!(dongle.commission(None, Duration::from_secs(30)).unwrap().devices.is_empty());
+        assert!(transport.is_finished());
+    }
+
+    #[test]
+    fn requests_give_up_when_interrupted() {
+        let (mut dongle, transport) = replay(vec![
+            (Direction::Tx, HandshakeRequest::new(0x1234).as_bytes()),
+        ]);
+
+        dongle.interrupt_flag().store(true, Ordering::SeqCst);
+        assert!(matches!(dongle.select_network(0x1234), Err(DongleError::Interrupted)));
+        assert!(transport.is_finished());
+    }
+
+    #[test]
+    fn retrying_reconnects_after_losing_the_dongle() {
+        // The capture ends before the dongle answers, as if it was unplugged
+        let (mut dongle, _) = replay(vec![(Direction::Tx, LockRequest::new().as_bytes())]);
+        let (reopened, transport) = replay(vec![
+            (Direction::Tx, BootRequest::new().as_bytes()),
+            (Direction::Rx, BootResponse::new(vec![0; 12], 0x0123_4567_89ab_cdef, 0).as_bytes()),
+            (Direction::Tx, BootConfirmRequest::new().as_bytes()),
+            (Direction::Rx, BootConfirmResponse::new().as_bytes()),
+            (Direction::Tx, LockRequest::new().as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+        ]);
+        let mut reopened = Some(reopened.serial);
+        dongle.set_connect(move || reopened.take().ok_or_else(|| io::ErrorKind::NotFound.into()));
+
+        dongle.retrying(|dongle| dongle.lock_network()).unwrap();
+        assert_eq!(dongle.info().unwrap().device_id(), 0x0123_4567_89ab_cdef);
+        assert!(transport.is_finished());
+    }
+
+    #[test]
+    fn retrying_leaves_other_failures_alone() {
+        let (mut dongle, transport) = replay(vec![
+            (Direction::Tx, UnlockRequest::new().as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+        ]);
+        dongle.set_connect(|| panic!("reconnected although the dongle is still there"));
+
+        let err = dongle.retrying(|dongle| dongle.lock_network()).unwrap_err();
+        assert!(!err.is_connection_lost(), "{}", err);
+        assert!(!transport.is_finished());
+    }
+
+    #[test]
+    fn keep_unlocked_leaves_the_network_open() {
+        let (mut dongle, transport) = replay(vec![
+            (Direction::Tx, UnlockRequest::new().as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+        ]);
+
+        dongle.unlock_network().unwrap().keep_unlocked();
+        // A lock request would have diverged from the capture
+        assert!(transport.is_finished());
     }
 
-    #[tokio_macros::test]
-    async fn can_open_new_session() {
-        let mut mock_serial = MockSerialPortTrait::new();
-
-        // Expectations for boot sequence
-        mock_serial.expect_write_data()
-            .with(eq([0x02, 0x40, 0x04, 0x00, 0x44].as_ref()))
-            .times(1)
-            .returning(|_| Ok(5));
-
-        mock_serial.expect_read_data()
-            .with(eq([0u8; 32].as_mut()))
-            .times(1)
-            .returning(|buf| {
-                buf.copy_from_slice(&[0x02, 0x40, 0x84, 0x16, 0x01, 0x00, 0x00, 0x87]);
-                Ok(8)
-            });
-
-        mock_serial.expect_usb_close()
-            .times(1);
-
-        let mut dongle = HackletDongle::open(mock_serial);
-        dongle.boot().await.unwrap();
-        dongle.close();
-    }
-
-    #[tokio_macros::test]
-    async fn can_find_new_device() {
-        let mut mock_serial = MockSerialPortTrait::new();
-
-        // Expectations for finding a new device
-        mock_serial.expect_write_data()
-            .with(eq([0x02, 0x40, 0x04, 0x00, 0x44].as_ref()))
-            .times(1)
-            .returning(|_| Ok(5));
-
-        mock_serial.expect_read_data()
-            .with(eq([0u8; 32].as_mut()))
-            .times(1)
-            .returning(|buf| {
-                buf.copy_from_slice(&[0x02, 0x40, 0x84, 0x16, 0x01, 0x00, 0x00, 0x87]);
-                Ok(8)
-            });
-
-        mock_serial.expect_usb_close()
-            .times(1);
-
-        let mut dongle = HackletDongle::open(mock_serial);
-        dongle.boot().await.unwrap();
-        dongle.close();
-    }
-
-    #[tokio_macros::test]
-    async fn can_request_sample() {
-        let mut mock_serial = MockSerialPortTrait::new();
-
-        // Expectations for requesting a sample
-        mock_serial.expect_write_data()
-            .with(eq([0x02, 0x40, 0x04, 0x00, 0x44].as_ref()))
-            .times(1)
-            .returning(|_| Ok(5));
-
-        mock_serial.expect_read_data()
-            .with(eq([0u8; 32].as_mut()))
-            .times(1)
-            .returning(|buf| {
-                buf.copy_from_slice(&[0x02, 0x40, 0x84, 0x16, 0x01, 0x00, 0x00, 0x87]);
-                Ok(8)
-            });
-
-        // Additional expectations for requesting sample...
-        mock_serial.expect_usb_close()
-            .times(1);
-
-        let mut dongle = HackletDongle::open(mock_serial);
-        dongle.request_sample().await.unwrap();
-        dongle.close();
-    }
-
-    #[tokio_macros::test]
-    async fn can_enable_socket() {
-        let mut mock_serial = MockSerialPortTrait::new();
-
-        // Expectations for enabling socket
-        mock_serial.expect_write_data()
-            .with(eq([0x02, 0x40, 0x04, 0x00, 0x44].as_ref()))
-            .times(1)
-            .returning(|_| Ok(5));
-
-        mock_serial.exp
+    #[test]
+    fn request_samples_reads_every_sample() {
+        let samples = vec![0x0a05, 0x0b07];
+        let response = SamplesResponse::new(0x1234, 1, 0x0000, 0, 0, samples.clone());
+        let (mut dongle, transport) = replay(vec![
+            (Direction::Tx, SamplesRequest::new(0x1234, 1).as_bytes()),
+            (Direction::Rx, AckResponse::new().as_bytes()),
+            (Direction::Rx, response.as_bytes()),
+        ]);
+
+        let response = dongle.request_samples(0x1234, 1).unwrap();
+        assert_eq!(response.samples, samples);
+        assert!(transport.is_finished());
     }
 }
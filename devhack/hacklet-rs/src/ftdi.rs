@@ -0,0 +1,227 @@
+use log::info;
+use libftdi1_sys::*;
+use ::libusb1_sys::{constants::LIBUSB_ERROR_NO_DEVICE, libusb_device, libusb_get_bus_number, libusb_get_device_address};
+use std::{
+    ffi::{CStr, CString},
+    io,
+    os::raw::c_char,
+    ptr,
+};
+
+use crate::serial_connection::SIO_DISABLE_FLOW_CTRL;
+use crate::transport::Transport;
+
+// USB ids of the Hacklet dongle's FTDI chip
+pub const VENDOR_ID: i32 = 0x0403;
+pub const PRODUCT_ID: i32 = 0x8c81;
+
+// What libftdi returns from a transfer once the device has gone away
+const FTDI_DEVICE_UNAVAILABLE: i32 = -666;
+
+// An attached dongle, as found by `list`
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct UsbDongle
+{
+    // Position in enumeration order, `open_dongle` takes it as `#<index>`
+    pub index: usize,
+    // `<bus>/<device>` as `open_dongle` takes it, changes when replugged
+    pub bus_path: String,
+    pub manufacturer: String,
+    pub description: String,
+    pub serial: String,
+}
+
+// Lists every attached Hacklet dongle
+pub fn list() -> io::Result<Vec<UsbDongle>>
+{
+    let context = unsafe { ftdi_new() };
+    if context.is_null() {
+        return Err(io::Error::other("ftdi_new failed"));
+    }
+
+    let mut devices: *mut ftdi_device_list = ptr::null_mut();
+    let found = unsafe { ftdi_usb_find_all(context, &mut devices, VENDOR_ID, PRODUCT_ID) };
+    let result = if found < 0 {
+        Err(error(context, "ftdi_usb_find_all", found))
+    } else {
+        let mut dongles = Vec::new();
+        let mut entry = devices;
+        while !entry.is_null() {
+            let (mut manufacturer, mut description, mut serial) = ([0 as c_char; 128], [0 as c_char; 128], [0 as c_char; 128]);
+            let status = unsafe {
+                ftdi_usb_get_strings(
+                    context,
+                    (*entry).dev,
+                    manufacturer.as_mut_ptr(),
+                    manufacturer.len() as i32,
+                    description.as_mut_ptr(),
+                    description.len() as i32,
+                    serial.as_mut_ptr(),
+                    serial.len() as i32,
+                )
+            };
+            // A dongle another process has open can't be asked for its strings,
+            // it is still listed so the indices line up
+            if status < 0 {
+                info!("Could not read strings of dongle #{}: {}", dongles.len(), error(context, "ftdi_usb_get_strings", status));
+            }
+            // libftdi only declares libusb's device type, it is the same C struct
+            let device = unsafe { (*entry).dev } as *mut libusb_device;
+            let (bus, address) = unsafe { (libusb_get_bus_number(device), libusb_get_device_address(device)) };
+            dongles.push(UsbDongle {
+                index: dongles.len(),
+                bus_path: format!("{:03}/{:03}", bus, address),
+                manufacturer: to_string(&manufacturer),
+                description: to_string(&description),
+                serial: to_string(&serial),
+            });
+            entry = unsafe { (*entry).next };
+        }
+        Ok(dongles)
+    };
+
+    unsafe {
+        ftdi_list_free(&mut devices);
+        ftdi_free(context);
+    }
+    result
+}
+
+// The Hacklet dongle's FTDI chip, driven through libftdi
+#[derive(Debug)]
+pub struct FtdiTransport
+{
+    context: *mut libftdi1_sys::ftdi_context,
+}
+
+impl FtdiTransport
+{
+    // Opens the first dongle found
+    pub fn open() -> io::Result<Self>
+    {
+        FtdiTransport::open_with(|context| unsafe { ftdi_usb_open(context, VENDOR_ID, PRODUCT_ID) })
+    }
+
+    // Opens a specific dongle, selected by serial number, by `#<index>` in the
+    // order `list` returns them, or by USB bus path as `<bus>/<device>`
+    // (ex. 002/005)
+    pub fn open_dongle(selector: &str) -> io::Result<Self>
+    {
+        let description = open_string(selector);
+        let description = CString::new(description).map_err(io::Error::other)?;
+
+        let transport = FtdiTransport::open_with(|context| unsafe { ftdi_usb_open_string(context, description.as_ptr()) })?;
+        info!("Opened dongle {}", selector);
+        Ok(transport)
+    }
+
+    fn open_with<F>(open: F) -> io::Result<Self>
+    where
+        F: FnOnce(*mut ftdi_context) -> i32
+    {
+        let context = unsafe { ftdi_new() };
+        if context.is_null() {
+            return Err(io::Error::other("ftdi_new failed"));
+        }
+
+        let status = open(context);
+        if status < 0 {
+            let err = error(context, "open", status);
+            unsafe { ftdi_free(context) };
+            return Err(err);
+        }
+
+        unsafe {
+            // Set bitmode and baudrate
+            ftdi_set_bitmode(context, 0x00, ftdi_mpsse_mode::BITMODE_RESET.0 as u8);
+            ftdi_set_baudrate(context, 115200);
+            ftdi_setflowctrl(context, SIO_DISABLE_FLOW_CTRL as i32);
+            ftdi_setdtr(context, 1);
+            ftdi_setrts(context, 1);
+        }
+
+        Ok(FtdiTransport { context })
+    }
+}
+
+impl Transport for FtdiTransport
+{
+    fn write(&mut self, data: &[u8]) -> io::Result<()>
+    {
+        let written = unsafe { ftdi_write_data(self.context, data.as_ptr(), data.len() as i32) };
+        if written < 0 {
+            return Err(transfer_error("ftdi_write_data", written));
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let chunk = unsafe { ftdi_read_data(self.context, buf.as_mut_ptr(), buf.len() as i32) };
+        if chunk < 0 {
+            return Err(transfer_error("ftdi_read_data", chunk));
+        }
+        Ok(chunk as usize)
+    }
+}
+
+impl Drop for FtdiTransport
+{
+    fn drop(&mut self)
+    {
+        unsafe {
+            ftdi_usb_close(self.context);
+            ftdi_free(self.context);
+        }
+        info!("Closed FTDI device connection");
+    }
+}
+
+// Builds the device string `ftdi_usb_open_string` understands
+fn open_string(selector: &str) -> String
+{
+    if let Some(index) = selector.strip_prefix('#') {
+        format!("i:0x{:04x}:0x{:04x}:{}", VENDOR_ID, PRODUCT_ID, index)
+    } else if selector.contains('/') {
+        format!("d:{}", selector)
+    } else {
+        format!("s:0x{:04x}:0x{:04x}:{}", VENDOR_ID, PRODUCT_ID, selector)
+    }
+}
+
+fn error(context: *mut ftdi_context, call: &str, status: i32) -> io::Error
+{
+    let message = unsafe { CStr::from_ptr(ftdi_get_error_string(context)) };
+    io::Error::other(format!("{} failed ({}): {}", call, status, message.to_string_lossy()))
+}
+
+// Tells an unplugged dongle apart from other failures, only the former is
+// worth reconnecting for
+fn transfer_error(call: &str, status: i32) -> io::Error
+{
+    let message = format!("{} failed ({})", call, status);
+    if status == FTDI_DEVICE_UNAVAILABLE || status == LIBUSB_ERROR_NO_DEVICE {
+        io::Error::new(io::ErrorKind::NotConnected, message)
+    } else {
+        io::Error::other(message)
+    }
+}
+
+fn to_string(buffer: &[c_char]) -> String
+{
+    let bytes: Vec<u8> = buffer.iter().take_while(|&&c| c != 0).map(|&c| c as u8).collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_dongles_by_serial_index_or_bus_path() {
+        assert_eq!(open_string("A1B2C3"), "s:0x0403:0x8c81:A1B2C3");
+        assert_eq!(open_string("#1"), "i:0x0403:0x8c81:1");
+        assert_eq!(open_string("002/005"), "d:002/005");
+    }
+}
@@ -0,0 +1,461 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex},
+    task::{Context, Poll},
+    thread,
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::sync::{oneshot, watch};
+
+use crate::{
+    dongle::{Dongle, DongleError, DongleInfo},
+    messages::responses::SamplesResponse,
+    serial_connection::SerialConnection,
+};
+
+// A cloneable, thread-safe handle to one dongle. The connection can't leave
+// the thread it was opened on, so a worker thread opens it, boots the dongle
+// and then runs the requests it is sent one at a time, in the order they
+// arrive. Every request gets its own reply, which can be awaited or waited on.
+//
+//     let handle = DongleHandle::spawn(SerialConnection::new)?;
+//     handle.switch(0x1234, 0, true).wait()?;           // from a thread
+//     handle.request_samples(0x1234, 0).await?;         // from async code
+//
+// When the dongle goes away (unplugged, USB reset) the worker keeps trying to
+// open it again, backing off between attempts, boots it and carries on with
+// everything queued. The request that was interrupted is run again only if it
+// is safe to repeat, and gives up after MAX_RETRIES. The worker stops once
+// every handle has been dropped.
+
+// Delay before the first reconnection attempt, doubled after every failure
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// Timeouts in a row after which a dongle that still seems attached is assumed
+// to be wedged and gets reopened
+const MAX_TIMEOUTS: u32 = 3;
+
+// Times a request is run again after losing the connection before its reply
+// is the error
+const MAX_RETRIES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum DongleState {
+    // Not connected, waiting to retry opening the dongle
+    Disconnected,
+    // Connected and running the boot sequence
+    Booting,
+    // Booted and answering requests
+    Ready,
+    // Connected, but the last request timed out
+    Degraded,
+}
+
+impl fmt::Display for DongleState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DongleState::Disconnected => write!(f, "disconnected"),
+            DongleState::Booting => write!(f, "booting"),
+            DongleState::Ready => write!(f, "ready"),
+            DongleState::Degraded => write!(f, "degraded"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DongleHandle {
+    jobs: mpsc::Sender<Job>,
+    state: watch::Receiver<DongleState>,
+    info: Arc<Mutex<Option<DongleInfo>>>,
+}
+
+// How a job went, so the worker knows whether to reconnect and whether to run
+// it again
+enum Outcome {
+    Done,
+    TimedOut,
+    // The connection went away and the job has replied with the error
+    ConnectionLost,
+    // The connection went away, the job wants to run again once it is back
+    Retry,
+}
+
+type Job = Box<dyn FnMut(&mut Dongle) -> Outcome + Send>;
+
+// The reply to one queued request
+#[must_use = "the request still runs, but its result is lost"]
+pub struct Pending<R> {
+    reply: oneshot::Receiver<Result<R, DongleError>>,
+}
+
+impl<R> Pending<R> {
+    // Blocks until the worker has run the request, not for use inside async code
+    pub fn wait(self) -> Result<R, DongleError> {
+        self.reply.blocking_recv().unwrap_or(Err(DongleError::Disconnected))
+    }
+}
+
+impl<R> Future for Pending<R> {
+    type Output = Result<R, DongleError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.reply).poll(cx).map(|reply| reply.unwrap_or(Err(DongleError::Disconnected)))
+    }
+}
+
+impl DongleHandle {
+    // Starts the worker. `connect` runs on the worker thread, so the
+    // connection it returns doesn't have to be `Send`. It is called again
+    // whenever the dongle has to be reopened.
+    pub fn spawn<F>(connect: F) -> io::Result<Self>
+    where
+        F: FnMut() -> io::Result<SerialConnection> + Send + 'static,
+    {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let (state, state_receiver) = watch::channel(DongleState::Disconnected);
+        let info = Arc::new(Mutex::new(None));
+        let worker = Worker { connect, queue, state, info: Arc::clone(&info) };
+        thread::Builder::new().name("hacklet-dongle".into()).spawn(move || worker.run())?;
+        Ok(DongleHandle { jobs, state: state_receiver, info })
+    }
+
+    // What the dongle reported the last time it booted, `None` until it has
+    pub fn info(&self) -> Option<DongleInfo> {
+        self.info.lock().unwrap().clone()
+    }
+
+    // The current state of the connection
+    pub fn state(&self) -> DongleState {
+        *self.state.borrow()
+    }
+
+    // A receiver that is notified on every state change, await
+    // `changed()` on it to follow the connection
+    pub fn subscribe(&self) -> watch::Receiver<DongleState> {
+        self.state.clone()
+    }
+
+    // Queues any operation on the dongle, it runs at most once. If the
+    // connection is lost while it runs there is no telling whether the dongle
+    // acted on it, so the reply is `DongleError::Disconnected`, as it is when
+    // the worker has stopped.
+    pub fn submit<R, F>(&self, job: F) -> Pending<R>
+    where
+        R: Send + 'static,
+        F: FnMut(&mut Dongle) -> Result<R, DongleError> + Send + 'static,
+    {
+        self.queue(false, job)
+    }
+
+    // Like `submit`, for operations that are safe to repeat: if the
+    // connection is lost while it runs it is run again after reconnecting, up
+    // to MAX_RETRIES times
+    pub fn submit_idempotent<R, F>(&self, job: F) -> Pending<R>
+    where
+        R: Send + 'static,
+        F: FnMut(&mut Dongle) -> Result<R, DongleError> + Send + 'static,
+    {
+        self.queue(true, job)
+    }
+
+    pub fn switch(&self, network_id: u16, channel_id: u16, state: bool) -> Pending<()> {
+        self.submit_idempotent(move |dongle| {
+            dongle.ensure_network(network_id)?;
+            dongle.switch(network_id, channel_id, state)
+        })
+    }
+
+    pub fn request_samples(&self, network_id: u16, channel_id: u16) -> Pending<SamplesResponse> {
+        self.submit_idempotent(move |dongle| {
+            dongle.ensure_network(network_id)?;
+            dongle.request_samples(network_id, channel_id)
+        })
+    }
+
+    pub fn lock_network(&self) -> Pending<()> {
+        self.submit_idempotent(|dongle| dongle.lock_network())
+    }
+
+    fn queue<R, F>(&self, idempotent: bool, mut job: F) -> Pending<R>
+    where
+        R: Send + 'static,
+        F: FnMut(&mut Dongle) -> Result<R, DongleError> + Send + 'static,
+    {
+        let (reply, receiver) = oneshot::channel();
+        let mut reply = Some(reply);
+        let mut retries = 0;
+        // A failed send drops the reply sender, which is reported as disconnected
+        let _ = self.jobs.send(Box::new(move |dongle: &mut Dongle| {
+            let mut result = job(dongle);
+            let outcome = match &result {
+                Err(err) if err.is_connection_lost() && idempotent && retries < MAX_RETRIES => {
+                    retries += 1;
+                    return Outcome::Retry;
+                }
+                Err(err) if err.is_connection_lost() => Outcome::ConnectionLost,
+                Err(DongleError::Timeout) => Outcome::TimedOut,
+                _ => Outcome::Done,
+            };
+            if matches!(outcome, Outcome::ConnectionLost) && !idempotent {
+                result = Err(DongleError::Disconnected);
+            }
+            if let Some(reply) = reply.take() {
+                let _ = reply.send(result);
+            }
+            outcome
+        }));
+        Pending { reply: receiver }
+    }
+}
+
+struct Worker<F> {
+    connect: F,
+    queue: mpsc::Receiver<Job>,
+    state: watch::Sender<DongleState>,
+    info: Arc<Mutex<Option<DongleInfo>>>,
+}
+
+impl<F> Worker<F>
+where
+    F: FnMut() -> io::Result<SerialConnection>,
+{
+    fn run(mut self) {
+        // The job the connection was lost in the middle of, run again first
+        let mut interrupted: Option<Job> = None;
+        let mut backoff = INITIAL_BACKOFF;
+
+        loop {
+            // Nobody left to serve, don't bother reconnecting
+            if interrupted.is_none() {
+                match self.queue.try_recv() {
+                    Ok(job) => interrupted = Some(job),
+                    Err(mpsc::TryRecvError::Empty) => {}
+                    Err(mpsc::TryRecvError::Disconnected) => break,
+                }
+            }
+
+            let mut dongle = match self.open() {
+                Ok(dongle) => dongle,
+                Err(err) => {
+                    self.set_state(DongleState::Disconnected);
+                    warn!("Could not open dongle, retrying in {:?}: {}", backoff, err);
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            };
+            self.set_state(DongleState::Ready);
+            backoff = INITIAL_BACKOFF;
+
+            let mut timeouts = 0;
+            loop {
+                let mut job = match interrupted.take() {
+                    Some(job) => job,
+                    None => match self.queue.recv() {
+                        Ok(job) => job,
+                        Err(_) => {
+                            info!("Dongle worker stopped");
+                            return;
+                        }
+                    },
+                };
+
+                match job(&mut dongle) {
+                    Outcome::Done => {
+                        timeouts = 0;
+                        self.set_state(DongleState::Ready);
+                    }
+                    Outcome::TimedOut => {
+                        timeouts += 1;
+                        self.set_state(DongleState::Degraded);
+                        if timeouts >= MAX_TIMEOUTS {
+                            warn!("Dongle stopped answering, reopening it");
+                            break;
+                        }
+                    }
+                    Outcome::ConnectionLost => {
+                        warn!("Lost the dongle, reconnecting");
+                        break;
+                    }
+                    Outcome::Retry => {
+                        warn!("Lost the dongle, reconnecting to retry the request");
+                        interrupted = Some(job);
+                        break;
+                    }
+                }
+            }
+            self.set_state(DongleState::Disconnected);
+        }
+    }
+
+    fn open(&mut self) -> Result<Dongle, DongleError> {
+        let serial = (self.connect)()?;
+        self.set_state(DongleState::Booting);
+        let mut dongle = Dongle::new(serial);
+        let info = dongle.boot()?;
+        // A different dongle may have been plugged in where the old one was
+        let mut current = self.info.lock().unwrap();
+        if current.as_ref().is_some_and(|current| current.device_id() != info.device_id()) {
+            warn!("Dongle changed to {}", info);
+        }
+        *current = Some(info);
+        drop(current);
+        Ok(dongle)
+    }
+
+    fn set_state(&self, state: DongleState) {
+        self.state.send_if_modified(|current| {
+            if *current == state {
+                return false;
+            }
+            info!("Dongle {}", state);
+            *current = state;
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    use crate::{
+        capture::Direction,
+        messages::{requests::*, responses::*},
+        replay::ReplayTransport,
+    };
+
+    // What the dongle sends and receives over one connection
+    type Frames = Vec<(Direction, Vec<u8>)>;
+
+    fn session(mut frames: Frames) -> Frames {
+        let mut session = vec![
+            (Direction::Tx, BootRequest::new().as_bytes()),
+            (Direction::Rx, BootResponse::new(vec![0; 12], 0x0123_4567_89ab_cdef, 0).as_bytes()),
+            (Direction::Tx, BootConfirmRequest::new().as_bytes()),
+            (Direction::Rx, BootConfirmResponse::new().as_bytes()),
+        ];
+        session.append(&mut frames);
+        session
+    }
+
+    // Each connection attempt plays the next session, or fails once they are
+    // used up. Returns how many attempts were made.
+    fn sessions(
+        sessions: Vec<Option<Frames>>,
+    ) -> (impl FnMut() -> io::Result<SerialConnection> + Send + 'static, Arc<Mutex<usize>>) {
+        let attempts = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&attempts);
+        let mut sessions = sessions.into_iter();
+        let connect = move || {
+            *counter.lock().unwrap() += 1;
+            match sessions.next() {
+                Some(Some(frames)) => Ok(SerialConnection::with_transport(Box::new(ReplayTransport::from_frames(frames)))),
+                _ => Err(io::Error::new(io::ErrorKind::NotFound, "no dongle")),
+            }
+        };
+        (connect, attempts)
+    }
+
+    #[tokio_macros::test]
+    async fn serialises_requests_from_threads_and_tasks() {
+        let mut request = ScheduleRequest::new(0x1234, 1);
+        request.always_on();
+        let samples = vec![0x0a05];
+        let (connect, _) = sessions(vec![Some(session(vec![
+            (Direction::Tx, HandshakeRequest::new(0x1234).as_bytes()),
+            (Direction::Rx, HandshakeResponse::new().as_bytes()),
+            (Direction::Tx, request.as_bytes()),
+            (Direction::Rx, ScheduleResponse::new().as_bytes()),
+            (Direction::Tx, SamplesRequest::new(0x1234, 1).as_bytes()),
+            (Direction::Rx, AckResponse::new().as_bytes()),
+            (Direction::Rx, SamplesResponse::new(0x1234, 1, 0x0000, 0, 0, samples.clone()).as_bytes()),
+        ]))]);
+        let handle = DongleHandle::spawn(connect).unwrap();
+
+        let other = handle.clone();
+        thread::spawn(move || other.switch(0x1234, 1, true).wait().unwrap()).join().unwrap();
+
+        let response = handle.request_samples(0x1234, 1).await.unwrap();
+        assert_eq!(response.samples, samples);
+        assert_eq!(handle.state(), DongleState::Ready);
+        assert_eq!(handle.info().unwrap().device_id(), 0x0123_4567_89ab_cdef);
+    }
+
+    #[test]
+    fn reconnects_and_retries_the_interrupted_request() {
+        let lock = vec![
+            (Direction::Tx, LockRequest::new().as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+        ];
+        // The first session ends right after booting, as if the dongle had
+        // been unplugged, then opening it fails once before it comes back
+        let (connect, attempts) = sessions(vec![Some(session(vec![])), None, Some(session(lock))]);
+        let handle = DongleHandle::spawn(connect).unwrap();
+
+        handle.lock_network().wait().unwrap();
+        assert_eq!(*attempts.lock().unwrap(), 3);
+        assert_eq!(handle.state(), DongleState::Ready);
+    }
+
+    #[test]
+    fn gives_up_on_a_request_that_keeps_losing_the_dongle() {
+        let (connect, attempts) = sessions(vec![Some(session(vec![])); MAX_RETRIES as usize + 1]);
+        let handle = DongleHandle::spawn(connect).unwrap();
+
+        let err = handle.lock_network().wait().unwrap_err();
+        assert!(err.is_connection_lost(), "{}", err);
+        assert_eq!(*attempts.lock().unwrap(), MAX_RETRIES as usize + 1);
+    }
+
+    #[test]
+    fn does_not_repeat_a_request_that_is_not_idempotent() {
+        let lock = vec![
+            (Direction::Tx, LockRequest::new().as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+        ];
+        // Had the request been repeated it would have succeeded on the second
+        // connection
+        let (connect, _) = sessions(vec![Some(session(vec![])), Some(session(lock))]);
+        let handle = DongleHandle::spawn(connect).unwrap();
+
+        let result = handle.submit(|dongle| dongle.lock_network()).wait();
+        assert!(matches!(result, Err(DongleError::Disconnected)));
+    }
+
+    #[test]
+    fn does_not_reconnect_for_other_io_errors() {
+        // The dongle is still there, the replay just expected another request
+        let (connect, attempts) = sessions(vec![Some(session(vec![
+            (Direction::Tx, UnlockRequest::new().as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+        ]))]);
+        let handle = DongleHandle::spawn(connect).unwrap();
+
+        let err = handle.lock_network().wait().unwrap_err();
+        assert!(matches!(err, DongleError::Io(_)) && !err.is_connection_lost(), "{}", err);
+        assert_eq!(*attempts.lock().unwrap(), 1);
+        assert_eq!(handle.state(), DongleState::Ready);
+    }
+
+    #[tokio_macros::test]
+    async fn reports_state_changes() {
+        let (connect, _) = sessions(vec![Some(session(vec![]))]);
+        let handle = DongleHandle::spawn(connect).unwrap();
+        let mut state = handle.subscribe();
+        state.wait_for(|state| *state == DongleState::Ready).await.unwrap();
+
+        // The replay is over, so the next request finds the dongle gone and
+        // it can't be opened again
+        let _pending = handle.lock_network();
+        state.wait_for(|state| *state == DongleState::Disconnected).await.unwrap();
+    }
+}
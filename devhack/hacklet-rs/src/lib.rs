@@ -0,0 +1,64 @@
+//! Protocol and driver for the Hacklet/Modlet USB dongle.
+//!
+//! `messages` has the wire format, `Dongle` (blocking), `AsyncDongle` and
+//! `DongleHandle` drive a dongle over any `Transport`. The `hacklet` binary is
+//! a thin CLI on top of this crate.
+//!
+//! By default the codec and the dongle logic are built, with no native
+//! dependencies. Integrations are opt-in cargo features: `ftdi` (USB dongles
+//! through libftdi), `tcp` (TCP transport and bridge), `sim` (replaying
+//! captured sessions) and `tokio` (the tokio based `AsyncDongle` and
+//! `DongleHandle`).
+//!
+//! Without the default `std` feature only `messages` is built, on `alloc`, so
+//! the codec can be used in firmware talking to the radio module directly.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+// Lets `#[derive(HackletMessage)]` refer to `::hacklet` from inside this crate
+extern crate self as hacklet;
+
+pub mod messages;
+#[cfg(feature = "std")]
+pub mod transport;
+#[cfg(feature = "std")]
+pub mod serial_connection;
+#[cfg(feature = "ftdi")]
+pub mod ftdi;
+#[cfg(feature = "tcp")]
+pub mod tcp;
+#[cfg(all(feature = "std", any(test, feature = "sim")))]
+pub mod replay;
+#[cfg(feature = "std")]
+pub mod capture;
+#[cfg(feature = "std")]
+pub mod decode;
+#[cfg(feature = "std")]
+pub mod pcapng;
+#[cfg(feature = "tcp")]
+pub mod bridge;
+#[cfg(feature = "std")]
+pub mod dongle;
+#[cfg(feature = "tokio")]
+pub mod async_dongle;
+#[cfg(feature = "tokio")]
+pub mod handle;
+
+#[cfg(feature = "tokio")]
+pub use async_dongle::AsyncDongle;
+#[cfg(feature = "std")]
+pub use dongle::{Commissioned, Dongle, DongleError, DongleInfo, UnlockedNetwork};
+#[cfg(feature = "tokio")]
+pub use handle::{DongleHandle, DongleState};
+#[cfg(feature = "std")]
+pub use serial_connection::SerialConnection;
+#[cfg(feature = "std")]
+pub use transport::Transport;
+
+// Used by the code `#[derive(HackletMessage)]` generates, not part of the API
+#[doc(hidden)]
+pub mod __private {
+    pub use alloc::vec::Vec;
+    pub use nom;
+}
@@ -1,8 +1,8 @@
-mod serial_connection;
-mod messages;
-mod dongle;
 mod command;
 mod version;
+mod registry;
+mod script;
+mod shell;
 
 fn main() {
     command::command();
@@ -0,0 +1,156 @@
+use nom::{
+    self,
+    bytes::streaming::{tag, take},
+    number::streaming::{be_u16, be_u8},
+    IResult,
+};
+use alloc::{vec, vec::Vec};
+use core::fmt;
+use super::{to_hex, Encoder, Message};
+
+// `BootConfirmRequest` goes on the wire as `02 40 00 01 41`, a length of one
+// and no payload, so the frame after it starts right after the checksum
+const BOOT_CONFIRM: (u16, u8) = (0x4000, 1);
+
+// A frame as it appears on the wire, without interpreting the payload:
+//
+//     0x02 | command (u16, big-endian) | payload length (u8) | payload | checksum
+//
+// The checksum is the XOR of the command, length and payload bytes, the same
+// scheme every message uses in `Message::calculate_checksum`.
+//
+// `payload_length` is the length byte as sent, which is not always the length
+// of the payload, see `BOOT_CONFIRM`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame
+{
+    pub header: u8,
+    pub command: u16,
+    pub payload_length: u8,
+    pub payload: Vec<u8>,
+    pub checksum: u8,
+}
+impl Frame
+{
+    pub fn new(command: u16, payload: Vec<u8>) -> Self
+    {
+        let mut frame = Frame {
+            header: 0x02,
+            command,
+            payload_length: payload.len() as u8,
+            payload,
+            checksum: 0,
+        };
+        frame.checksum = frame.calculate_checksum();
+        frame
+    }
+    // The bytes as received, including a checksum that may be wrong.
+    // `encode_into` writes the correct one instead.
+    pub fn as_bytes(&self) -> Vec<u8>
+    {
+        let mut buffer = vec![self.header];
+        buffer.extend_from_slice(&self.command.to_be_bytes());
+        buffer.push(self.payload_length);
+        buffer.extend_from_slice(&self.payload);
+        buffer.push(self.checksum);
+        buffer
+    }
+    pub fn checksum_valid(&self) -> bool
+    {
+        self.checksum == self.calculate_checksum()
+    }
+}
+impl Message for Frame
+{
+    fn encode(&self, encoder: &mut Encoder)
+    {
+        encoder.header(self.header);
+        encoder.bytes(&self.command.to_be_bytes());
+        encoder.u8(self.payload_length);
+        encoder.bytes(&self.payload);
+    }
+    // Consumes exactly one frame, returning the bytes that follow it
+    fn read(input: &[u8]) -> IResult<&[u8], Self>
+    {
+        let (input, header) = tag(&[0x02u8][..])(input)?;
+        let (input, command) = be_u16(input)?;
+        let (input, payload_length) = be_u8(input)?;
+        let sent = if (command, payload_length) == BOOT_CONFIRM { 0 } else { payload_length };
+        let (input, payload) = take(sent)(input)?;
+        let (input, checksum) = be_u8(input)?;
+
+        Ok((
+            input,
+            Frame {
+                header: header[0],
+                command,
+                payload_length,
+                payload: payload.to_vec(),
+                checksum,
+            },
+        ))
+    }
+}
+impl fmt::Display for Frame
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(
+            f,
+            "command 0x{:04x}, length {}, payload [{}], checksum 0x{:02x}",
+            self.command,
+            self.payload_length,
+            to_hex(&self.payload),
+            self.checksum
+        )?;
+        if self.checksum_valid() {
+            write!(f, " ok")
+        } else {
+            write!(f, " bad (expected 0x{:02x})", self.calculate_checksum())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::requests::{BootConfirmRequest, BootRequest, LockRequest};
+
+    #[test]
+    fn builds_frames_like_the_message_types() {
+        let frame = Frame::new(0x4004, vec![]);
+        assert_eq!(frame.as_bytes(), BootRequest::new().as_bytes());
+    }
+
+    #[test]
+    fn reads_one_frame_and_returns_the_rest() {
+        let bytes = [0x02, 0x40, 0x24, 0x01, 0x00, 0x65, 0x02, 0x40];
+        let (rest, frame) = Frame::read(&bytes).unwrap();
+        assert_eq!(frame, Frame::new(0x4024, vec![0x00]));
+        assert!(frame.checksum_valid());
+        assert_eq!(rest, &[0x02, 0x40]);
+        assert!(matches!(Frame::read(rest), Err(nom::Err::Incomplete(_))));
+    }
+
+    #[test]
+    fn reads_the_boot_confirm_request_without_a_payload() {
+        let mut bytes = BootConfirmRequest::new().as_bytes();
+        bytes.extend(LockRequest::new().as_bytes());
+        assert_eq!(&bytes[..5], &[0x02, 0x40, 0x00, 0x01, 0x41]);
+
+        let (rest, frame) = Frame::read(&bytes).unwrap();
+        assert_eq!(frame.payload, vec![]);
+        assert!(frame.checksum_valid());
+        assert_eq!(frame.as_bytes(), BootConfirmRequest::new().as_bytes());
+        let (rest, frame) = Frame::read(rest).unwrap();
+        assert_eq!(frame.as_bytes(), LockRequest::new().as_bytes());
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn flags_bad_checksums() {
+        let (_, frame) = Frame::read(&[0x02, 0x40, 0x80, 0x01, 0x10, 0x01]).unwrap();
+        assert!(!frame.checksum_valid());
+        assert!(frame.to_string().ends_with("bad (expected 0xd1)"));
+    }
+}
@@ -1,37 +1,347 @@
+use alloc::{format, string::String, vec::Vec};
 use nom::{
     self,
+    combinator::verify,
+    number::streaming::{be_u16, be_u8},
     IResult,
 };
 
 pub mod responses;
 pub mod requests;
+pub mod frame;
+
+// Header, command, payload length and payload plus the checksum byte. The
+// length is a single byte, so no frame is longer than this.
+pub const MAX_FRAME_LEN: usize = 4 + 255 + 1;
 
 pub trait Message
 {
-    fn calculate_checksum(&self) -> u8;
+    // Writes the frame up to, but not including, its checksum
+    fn encode(&self, encoder: &mut Encoder);
+    // Consumes exactly one frame and returns the bytes after it, so reads can
+    // be chained over a stream buffer or combined with nom's `alt`/`many0`
     fn read(bytes: &[u8]) -> IResult<&[u8], Self>
     where
         Self: Sized;
+
+    // XOR of every byte after the header, computed without serialising
+    fn calculate_checksum(&self) -> u8
+    {
+        let mut encoder = Encoder::measure();
+        self.encode(&mut encoder);
+        encoder.checksum
+    }
+
+    fn encoded_len(&self) -> usize
+    {
+        let mut encoder = Encoder::measure();
+        self.encode(&mut encoder);
+        encoder.len + 1
+    }
+
+    // Writes the whole frame into `buffer` and returns its length. The checksum
+    // is computed while encoding rather than copied from the message. Panics if
+    // `buffer` is shorter than `encoded_len()`, `MAX_FRAME_LEN` always fits.
+    fn encode_into(&self, buffer: &mut [u8]) -> usize
+    {
+        let mut encoder = Encoder::new(buffer);
+        self.encode(&mut encoder);
+        let checksum = encoder.checksum;
+        encoder.u8(checksum);
+        encoder.len
+    }
+}
+
+// Writes fields into a caller provided buffer, keeping the running checksum.
+// With no buffer it only measures, which is how the checksum and length of a
+// message are found without allocating.
+pub struct Encoder<'a>
+{
+    buffer: Option<&'a mut [u8]>,
+    len: usize,
+    checksum: u8,
+}
+impl<'a> Encoder<'a>
+{
+    fn new(buffer: &'a mut [u8]) -> Self
+    {
+        Encoder { buffer: Some(buffer), len: 0, checksum: 0 }
+    }
+    fn measure() -> Self
+    {
+        Encoder { buffer: None, len: 0, checksum: 0 }
+    }
+    // The header is the only byte not covered by the checksum
+    pub fn header(&mut self, header: u8)
+    {
+        self.put(&[header]);
+    }
+    pub fn u8(&mut self, value: u8)
+    {
+        self.bytes(&[value]);
+    }
+    pub fn bytes(&mut self, bytes: &[u8])
+    {
+        self.put(bytes);
+        self.checksum = bytes.iter().fold(self.checksum, |acc, &x| acc ^ x);
+    }
+    fn put(&mut self, bytes: &[u8])
+    {
+        if let Some(buffer) = self.buffer.as_deref_mut() {
+            buffer[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        }
+        self.len += bytes.len();
+    }
+}
+
+
+// Parses the header, command and payload length of a frame. A command or
+// length other than the expected one is a parse error rather than
+// `Incomplete`, so message parsers can be tried in turn with `alt`. Commands
+// shared by a request and its acknowledgement are told apart by the length.
+pub fn frame_header<'a>(command: u16, payload_length: Option<u8>) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], (u8, u16, u8)>
+{
+    move |input| {
+        let (input, header) = verify(be_u8, |&header| header == 0x02)(input)?;
+        let (input, command) = verify(be_u16, |&code| code == command)(input)?;
+        let (input, length) = verify(be_u8, |&length| payload_length.is_none_or(|expected| expected == length))(input)?;
+        Ok((input, (header, command, length)))
+    }
+}
+
+// Formats bytes as space separated hex, e.g. "02 40 04 00 44"
+pub fn to_hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ")
+}
+
+// Parses hex written with or without separating whitespace, e.g. "0240 04 00 44"
+pub fn parse_hex(text: &str) -> Option<Vec<u8>>
+{
+    let digits: String = text.split_whitespace().map(|word| word.trim_start_matches("0x")).collect();
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| digits.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+// Encodes a message, reads it back and checks both agree with the generic
+// frame reader, so a payload_length that disagrees with the fields shows up
+#[cfg(test)]
+pub(crate) fn round_trip<M: Message>(message: &M) -> M
+{
+    let mut buffer = [0u8; MAX_FRAME_LEN];
+    let len = message.encode_into(&mut buffer);
+    let bytes = &buffer[..len];
+    assert_eq!(len, message.encoded_len());
+
+    let (rest, frame) = frame::Frame::read(bytes).unwrap();
+    assert!(rest.is_empty(), "frame reader left {}", to_hex(rest));
+    assert!(frame.checksum_valid());
+
+    let (rest, read) = M::read(bytes).unwrap();
+    assert!(rest.is_empty(), "message read left {}", to_hex(rest));
+    let mut again = [0u8; MAX_FRAME_LEN];
+    let len = read.encode_into(&mut again);
+    assert_eq!(&again[..len], bytes);
+    read
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::messages::responses::BootConfirmResponse;
-    use crate::messages::requests::BootRequest;
+    use crate::messages::{parse_hex, to_hex};
+    use crate::messages::frame::Frame;
+    use crate::messages::responses::{AckResponse, BootConfirmResponse, BootResponse, BootView, BroadcastResponse, LockResponse, Response, SamplesResponse, SamplesView};
+    use crate::messages::requests::{BootRequest, LockRequest, UnlockRequest, UpdateTimeRequest};
+    use nom::{branch::alt, combinator::{complete, map}, multi::many0};
+    use crate::messages::{Message, MAX_FRAME_LEN};
+
+    #[test]
+    fn hex_round_trips() {
+        let bytes = parse_hex("02 40 04 0044").unwrap();
+        assert_eq!(bytes, vec![0x02, 0x40, 0x04, 0x00, 0x44]);
+        assert_eq!(to_hex(&bytes), "02 40 04 00 44");
+        assert_eq!(parse_hex("0x4024"), Some(vec![0x40, 0x24]));
+        assert_eq!(parse_hex("024"), None);
+        assert_eq!(parse_hex("zz"), None);
+    }
+
+    #[test]
+    fn update_time_request_takes_the_time_from_the_caller() {
+        let request = UpdateTimeRequest::new(0x1234, 0x6553_f100);
+        assert_eq!(request.as_bytes(), vec![0x02, 0x40, 0x22, 0x06, 0x12, 0x34, 0x00, 0xf1, 0x53, 0x65, 0x85]);
+        let (_, read) = UpdateTimeRequest::read(&request.as_bytes()).unwrap();
+        assert_eq!(read.time, 0x6553_f100);
+    }
+
+    #[test]
+    fn encodes_into_a_caller_buffer_in_one_pass() {
+        let response = SamplesResponse::new(0x1234, 1, 0, 0x6553_f100, 0x0a0b0c, vec![0x0102, 0x0304]);
+        let mut buffer = [0xffu8; MAX_FRAME_LEN];
+        let len = response.encode_into(&mut buffer);
+        assert_eq!(len, response.encoded_len());
+        assert_eq!(&buffer[..len], &response.as_bytes()[..]);
+        assert_eq!(buffer[len - 1], response.checksum());
+        assert_eq!(&buffer[15..18], &[0x0c, 0x0b, 0x0a]);
+    }
 
     #[test]
-    fn boot_confirm_response_detects_invalid_checksum() {
+    fn views_borrow_from_the_input_and_return_the_rest() {
+        let response = SamplesResponse::new(0x1234, 1, 0, 0x6553_f100, 0x0a0b0c, vec![0x0102, 0x0304]);
+        let boot = BootResponse::new((1..=12).collect(), 0x0123_4567_89ab_cdef, 0x0506);
+        let mut stream = response.as_bytes();
+        stream.extend(boot.as_bytes());
+        stream.push(0x02);
+
+        let (rest, samples) = SamplesView::read(&stream).unwrap();
+        assert_eq!(samples.network_id(), 0x1234);
+        assert_eq!(samples.stored_sample_count(), 0x0a0b0c);
+        assert_eq!(samples.samples().collect::<Vec<_>>(), vec![0x0102, 0x0304]);
+        assert_eq!(samples.to_response().as_bytes(), response.as_bytes());
+
+        let (rest, view) = BootView::read(rest).unwrap();
+        assert_eq!(view.data(), &boot.data[..]);
+        assert_eq!(view.device_id(), 0x0123_4567_89ab_cdef);
+        assert_eq!(rest, &[0x02]);
+        assert!(matches!(SamplesView::read(rest), Err(nom::Err::Incomplete(_))));
+    }
+
+    #[test]
+    fn reads_compose_with_nom_combinators() {
+        #[derive(Debug, PartialEq)]
+        enum Seen { Unlock, Lock, Broadcast(u64) }
+
+        let mut stream = LockRequest::new().as_bytes();
+        stream.extend(UnlockRequest::new().as_bytes());
+        stream.extend(BroadcastResponse::new(0x1234, 0xdeadbeef, 0).as_bytes());
+        stream.extend(&[0x02, 0xa0]);
+
+        let mut parse = many0(complete(alt((
+            map(UnlockRequest::read, |_| Seen::Unlock),
+            map(LockRequest::read, |_| Seen::Lock),
+            map(BroadcastResponse::read, |response| Seen::Broadcast(response.device_id)),
+        ))));
+        let (rest, seen) = parse(&stream).unwrap();
+        assert_eq!(seen, vec![Seen::Lock, Seen::Unlock, Seen::Broadcast(0xdeadbeef)]);
+        assert_eq!(rest, &[0x02, 0xa0]);
+        assert!(matches!(BroadcastResponse::read(rest), Err(nom::Err::Incomplete(_))));
+        assert!(matches!(LockResponse::read(&stream), Err(nom::Err::Error(_))));
+    }
+
+    #[test]
+    fn response_dispatches_by_command_code() {
+        let mut stream = AckResponse::new().as_bytes();
+        stream.extend(SamplesResponse::new(0x1234, 1, 0, 0, 0, vec![0x0102]).as_bytes());
+        stream.extend(Frame::new(0x1234, vec![0xab]).as_bytes());
+        stream.extend(Frame::new(0x4084, vec![0x00]).as_bytes());
+
+        let (rest, ack) = Response::read(&stream).unwrap();
+        assert!(matches!(ack, Response::Ack(_)));
+        let (rest, samples) = Response::read(rest).unwrap();
+        assert!(matches!(samples, Response::Samples(ref response) if response.samples == vec![0x0102]));
+        let (rest, unknown) = Response::read(rest).unwrap();
+        assert!(matches!(unknown, Response::Unknown { command: 0x1234, ref payload } if payload == &vec![0xab]));
+        // A boot response is 22 bytes of payload, not one
+        let (rest, odd) = Response::read(rest).unwrap();
+        assert!(matches!(odd, Response::Unknown { command: 0x4084, ref payload } if payload == &vec![0x00]));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn response_keeps_reading_after_an_odd_frame() {
+        // A samples response whose sample count does not fit its length
+        let mut stream = Frame::new(0x40A4, vec![0x12, 0x34, 0x00]).as_bytes();
+        stream.extend(LockResponse::new().as_bytes());
+
+        let mut parse = many0(complete(Response::read));
+        let (rest, responses) = parse(&stream).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(responses.len(), 2);
+        assert!(matches!(responses[0], Response::Unknown { command: 0x40A4, .. }));
+        assert!(matches!(responses[1], Response::Lock(_)));
+    }
+
+    #[test]
+    fn boot_confirm_response_keeps_an_invalid_checksum_for_the_caller() {
         let bad_checksum = vec![0x02, 0x40, 0x80, 0x01, 0x10, 0x01];
 
-        // Expect an error when reading the invalid checksum
-        let result = BootConfirmResponse::read(&bad_checksum);
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "Invalid checksum");
+        // Reads don't check the checksum, the caller compares it
+        let (_, response) = BootConfirmResponse::read(&bad_checksum).unwrap();
+        assert_eq!(response.checksum(), 0x01);
+        assert_eq!(response.calculate_checksum(), 0xd1);
+        let (_, frame) = Frame::read(&bad_checksum).unwrap();
+        assert!(!frame.checksum_valid());
     }
 
     #[test]
     fn boot_request_has_proper_checksum() {
         let request = BootRequest::new();
-        assert_eq!(request.checksum().get(), 0x44); // Check the checksum
+        assert_eq!(request.checksum(), 0x44); // Check the checksum
+    }
+
+    // The derived codecs against frames written out by hand, one per attribute
+
+    #[test]
+    fn derived_le_fields_are_little_endian() {
+        let bytes = parse_hex("02 40 22 06 12 34 04 03 02 01 46").unwrap();
+        let (rest, request) = UpdateTimeRequest::read(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(request.network_id, 0x1234);
+        assert_eq!(request.time, 0x0102_0304);
+        assert_eq!(UpdateTimeRequest::new(0x1234, 0x0102_0304).as_bytes(), bytes);
+    }
+
+    #[test]
+    fn derived_three_byte_fields_carry_the_low_bytes() {
+        let bytes = parse_hex("02 40 a4 0e 12 34 00 01 00 00 00 00 00 00 00 0c 0b 0a c0").unwrap();
+        let (_, response) = SamplesResponse::read(&bytes).unwrap();
+        assert_eq!(response.stored_sample_count, 0x0a0b0c);
+        assert_eq!(SamplesResponse::new(0x1234, 1, 0, 0, 0x0a0b0c, vec![]).as_bytes(), bytes);
+    }
+
+    #[test]
+    fn derived_len_fields_take_a_fixed_number_of_bytes() {
+        let bytes = parse_hex("02 40 84 16 01 02 03 04 05 06 07 08 09 0a 0b 0c 01 23 45 67 89 ab cd ef 05 06 dd").unwrap();
+        let (rest, response) = BootResponse::read(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(response.data, (1..=12).collect::<Vec<u8>>());
+        assert_eq!(response.device_id, 0x0123_4567_89ab_cdef);
+        assert_eq!(response.data2, 0x0506);
+        assert_eq!(response.checksum(), 0xdd);
+        assert_eq!(BootResponse::new((1..=12).collect(), 0x0123_4567_89ab_cdef, 0x0506).as_bytes(), bytes);
+    }
+
+    #[test]
+    fn derived_count_fields_read_as_many_items_as_counted() {
+        let bytes = parse_hex("02 40 a4 12 12 34 00 01 00 00 00 00 00 00 02 00 00 00 02 01 04 03 d7").unwrap();
+        let (rest, response) = SamplesResponse::read(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(response.sample_count, 2);
+        assert_eq!(response.samples, vec![0x0102, 0x0304]);
+        assert_eq!(SamplesResponse::new(0x1234, 1, 0, 0, 0, vec![0x0102, 0x0304]).as_bytes(), bytes);
+    }
+
+    #[test]
+    fn derived_count_fields_have_to_fill_the_payload() {
+        // Three samples counted in a payload with room for two
+        let short = parse_hex("02 40 a4 12 12 34 00 01 00 00 00 00 00 00 03 00 00 00 02 01 04 03 d6 02 a0").unwrap();
+        assert!(matches!(SamplesResponse::read(&short), Err(nom::Err::Error(_))));
+        assert!(matches!(SamplesView::read(&short), Err(nom::Err::Error(_))));
+        // One sample counted in a payload of two
+        let long = parse_hex("02 40 a4 12 12 34 00 01 00 00 00 00 00 00 01 00 00 00 02 01 04 03 d4").unwrap();
+        assert!(matches!(SamplesResponse::read(&long), Err(nom::Err::Error(_))));
+        assert!(matches!(SamplesView::read(&long), Err(nom::Err::Error(_))));
+    }
+
+    #[test]
+    fn derived_expect_fields_tell_unlock_from_lock() {
+        let bytes = parse_hex("02 a2 36 04 fc ff 90 01 02").unwrap();
+        let (_, request) = UnlockRequest::read(&bytes).unwrap();
+        assert_eq!(request.data, 0xfcff_9001);
+        assert_eq!(UnlockRequest::new().as_bytes(), bytes);
+        assert!(matches!(LockRequest::read(&bytes), Err(nom::Err::Error(_))));
     }
 }
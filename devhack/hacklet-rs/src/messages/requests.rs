@@ -1,20 +1,20 @@
-use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
-use nom::{
-    self,
-    Err, IResult, Needed,
-};
-use std::{
-    io::Write, time::{SystemTime, UNIX_EPOCH}
-};
+use alloc::{vec, vec::Vec};
+use hacklet_derive::HackletMessage;
 use super::Message;
 
-#[derive(Debug)]
+// Lock and unlock share a command and tell the dongle apart by their payload
+const UNLOCK_DATA: u32 = 0xFCFF9001;
+const LOCK_DATA: u32 = 0xFCFF0001;
+
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0x4004, payload_length = 0)]
+#[non_exhaustive]
 pub struct BootRequest
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
-    pub checksum: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
+    checksum: u8,
 }
 impl BootRequest
 {
@@ -29,47 +29,24 @@ impl BootRequest
         req.checksum = req.calculate_checksum();
         req
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
 }
-impl Message for BootRequest
+impl Default for BootRequest
 {
-    fn calculate_checksum(&self) -> u8
+    fn default() -> Self
     {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
-    }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
-    {
-        if input.is_empty() || input.len() < 5 {
-            return Err(Err::Incomplete(Needed::new(5)));
-        }
-        let mut request = BootRequest::new();
-        request.header = input[0];
-        request.command = u16::from_be_bytes([input[1], input[2]]);
-        request.payload_length = input[3];
-        request.checksum = input[4];
-
-        Ok((input, request))
+        BootRequest::new()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0x4000, payload_length = 1, no_payload)]
+#[non_exhaustive]
 pub struct BootConfirmRequest
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
-    pub checksum: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
+    checksum: u8,
 }
 impl BootConfirmRequest
 {
@@ -84,48 +61,26 @@ impl BootConfirmRequest
         req.checksum = req.calculate_checksum();
         req
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
 }
-impl Message for BootConfirmRequest
+impl Default for BootConfirmRequest
 {
-    fn calculate_checksum(&self) -> u8
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
-    }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
+    fn default() -> Self
     {
-        if input.is_empty() || input.len() < 5 {
-            return Err(Err::Incomplete(Needed::new(5)));
-        }
-        let mut request = BootConfirmRequest::new();
-        request.header = input[0];
-        request.command = u16::from_be_bytes([input[1], input[2]]);
-        request.payload_length = input[3];
-        request.checksum = input[4];
-
-        Ok((input, request))
+        BootConfirmRequest::new()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0xA236, payload_length = 4)]
+#[non_exhaustive]
 pub struct UnlockRequest
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
+    #[hacklet(expect = UNLOCK_DATA)]
     pub data: u32,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl UnlockRequest
 {
@@ -135,57 +90,32 @@ impl UnlockRequest
             header: 0x02,      // Default header
             command: 0xA236,   // Default command
             payload_length: 4, // Default payload length
-            data: 0xFCFF9001,  // Default time
+            data: UNLOCK_DATA,
             checksum: 0,
         };
         req.checksum = req.calculate_checksum();
         req
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u32::<BigEndian>(self.data).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
 }
-impl Message for UnlockRequest
+impl Default for UnlockRequest
 {
-    fn calculate_checksum(&self) -> u8
+    fn default() -> Self
     {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u32::<BigEndian>(self.data).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
-    }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
-    {
-        if input.is_empty() || input.len() < 9 {
-            return Err(Err::Incomplete(Needed::new(9)));
-        }
-        let mut request = UnlockRequest::new();
-        request.header = input[0];
-        request.command = u16::from_be_bytes([input[1], input[2]]);
-        request.payload_length = input[3];
-        request.data = u32::from_be_bytes(input[4..8].try_into().unwrap());
-        request.checksum = input[8];
-
-        Ok((input, request))
+        UnlockRequest::new()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0xA236, payload_length = 4)]
+#[non_exhaustive]
 pub struct LockRequest
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
+    #[hacklet(expect = LOCK_DATA)]
     pub data: u32,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl LockRequest
 {
@@ -195,67 +125,39 @@ impl LockRequest
             header: 0x02,      // Default header
             command: 0xA236,   // Default command
             payload_length: 4, // Default payload length
-            data: 0xFCFF0001,  // Default time
+            data: LOCK_DATA,
             checksum: 0,       // TODO: calculate_checksum
         };
         req.checksum = req.calculate_checksum();
         req
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u32::<BigEndian>(self.data).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
 }
-impl Message for LockRequest
+impl Default for LockRequest
 {
-    fn calculate_checksum(&self) -> u8
+    fn default() -> Self
     {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u32::<BigEndian>(self.data).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
-    }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
-    {
-        if input.is_empty() || input.len() < 9 {
-            return Err(Err::Incomplete(Needed::new(9)));
-        }
-        let mut request = LockRequest::new();
-        request.header = input[0];
-        request.command = u16::from_be_bytes([input[1], input[2]]);
-        request.payload_length = input[3];
-        request.data = u32::from_be_bytes(input[4..8].try_into().unwrap());
-        request.checksum = input[8];
-
-        Ok((input, request))
+        LockRequest::new()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0x4022, payload_length = 6)]
+#[non_exhaustive]
 pub struct UpdateTimeRequest
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
     pub network_id: u16,
+    #[hacklet(le)]
     pub time: u32,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl UpdateTimeRequest
 {
-    pub fn new(network_id: u16) -> Self
+    // `time` is seconds since the Unix epoch, the dongle has no clock of its own
+    pub fn new(network_id: u16, time: u32) -> Self
     {
-        let time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs() as u32;
         let mut req = UpdateTimeRequest {
             header: 0x02,      // Default header
             command: 0x4022,   // Default command
@@ -267,55 +169,29 @@ impl UpdateTimeRequest
         req.checksum = req.calculate_checksum();
         req
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u16::<BigEndian>(self.network_id).unwrap();
-        buffer.write_u32::<LittleEndian>(self.time).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
-}
-impl Message for UpdateTimeRequest
-{
-    fn calculate_checksum(&self) -> u8
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u16::<BigEndian>(self.network_id).unwrap();
-        buffer.write_u32::<LittleEndian>(self.time).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
-    }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
+    // Sets the network's clock to the system time
+    #[cfg(feature = "std")]
+    pub fn now(network_id: u16) -> Self
     {
-        if input.is_empty() || input.len() < 11 {
-            return Err(Err::Incomplete(Needed::new(11)));
-        }
-        let network_id = u16::from_be_bytes([input[4], input[5]]);
-        let mut request = UpdateTimeRequest::new(network_id);
-        request.header = input[0];
-        request.command = u16::from_be_bytes([input[1], input[2]]);
-        request.payload_length = input[3];
-        request.time = u32::from_le_bytes(input[6..10].try_into().unwrap());
-        request.checksum = input[10];
-
-        Ok((input, request))
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs() as u32;
+        UpdateTimeRequest::new(network_id, time)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0x4003, payload_length = 4)]
+#[non_exhaustive]
 pub struct HandshakeRequest
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
     pub network_id: u16,
     pub data: u16,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl HandshakeRequest
 {
@@ -332,56 +208,20 @@ impl HandshakeRequest
         req.checksum = req.calculate_checksum(); // Set checksum based on the other fields
         req
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u16::<BigEndian>(self.network_id).unwrap();
-        buffer.write_u16::<BigEndian>(self.data).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
-}
-impl Message for HandshakeRequest
-{
-    fn calculate_checksum(&self) -> u8
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u16::<BigEndian>(self.network_id).unwrap();
-        buffer.write_u16::<BigEndian>(self.data).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
-    }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
-    {
-        if input.is_empty() || input.len() < 9 {
-            return Err(Err::Incomplete(Needed::new(9)));
-        }
-        let network_id = u16::from_be_bytes([input[4], input[5]]);
-        let mut request = HandshakeRequest::new(network_id);
-        request.header = input[0];
-        request.command = u16::from_be_bytes([input[1], input[2]]);
-        request.payload_length = input[3];
-        request.data = u16::from_be_bytes([input[6], input[7]]);
-        request.checksum = input[8];
-
-        Ok((input, request))
-    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0x4024, payload_length = 6)]
+#[non_exhaustive]
 pub struct SamplesRequest
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
     pub network_id: u16,
     pub channel_id: u16,
     pub data: u16,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl SamplesRequest
 {
@@ -399,65 +239,27 @@ impl SamplesRequest
         req.checksum = req.calculate_checksum(); // Set checksum based on the other fields
         req
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u16::<BigEndian>(self.network_id).unwrap();
-        buffer.write_u16::<BigEndian>(self.channel_id).unwrap();
-        buffer.write_u16::<BigEndian>(self.data).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
 }
 
-impl Message for SamplesRequest
-{
-    fn calculate_checksum(&self) -> u8
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u16::<BigEndian>(self.network_id).unwrap();
-        buffer.write_u16::<BigEndian>(self.channel_id).unwrap();
-        buffer.write_u16::<BigEndian>(self.data).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
-    }
-
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
-    {
-        if input.is_empty() || input.len() < 10 {
-            return Err(Err::Incomplete(Needed::new(27)));
-        }
-        let network_id = u16::from_be_bytes([input[3], input[4]]);
-        let channel_id = u16::from_be_bytes([input[5], input[6]]);
-        let mut request = SamplesRequest::new(network_id, channel_id);
-        request.header = input[0];
-        request.command = u16::from_be_bytes([input[1], input[2]]);
-        request.payload_length = input[3];
-        request.data = u16::from_be_bytes([input[7], input[8]]);
-        request.checksum = input[9];
 
-        Ok((input, request))
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0x4023, payload_length = 59)]
+#[non_exhaustive]
 pub struct ScheduleRequest
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
     pub network_id: u16,
-    pub channel_id: u16,
+    // One byte here, unlike the samples request
+    pub channel_id: u8,
+    #[hacklet(len = 56)]
     pub schedule: Vec<u8>,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl ScheduleRequest
 {
-    pub fn new(network_id: u16, channel_id: u16) -> Self
+    pub fn new(network_id: u16, channel_id: u8) -> Self
     {
         let mut req = ScheduleRequest {
             header: 0x02,             // Default header
@@ -465,65 +267,82 @@ impl ScheduleRequest
             payload_length: 59,       // Default payload length
             network_id,          // TODO: network_id
             channel_id,          // TODO: channel_id
-            schedule: vec![0x00, 56], // Default time
+            schedule: vec![0x00; 56], // Default time
             checksum: 0,              // TODO: calculate_checksum
         };
         req.checksum = req.calculate_checksum(); // Set checksum based on the other fields
         req
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u16::<BigEndian>(self.network_id).unwrap();
-        buffer.write_u16::<BigEndian>(self.channel_id).unwrap();
-        buffer.write_all(&self.schedule).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
     pub fn always_on(&mut self) 
     {
-        let mut bitmap = vec![0x7f, 56];
+        let mut bitmap = vec![0x7f; 56];
         bitmap[5] = 0x25;
         self.schedule = bitmap;
-
+        self.checksum = self.calculate_checksum();
     }
     pub fn always_off(&mut self) 
     {
-        let mut bitmap = vec![0x7f, 56];
+        let mut bitmap = vec![0x7f; 56];
         bitmap[5] = 0xa5;
         self.schedule = bitmap;
-
+        self.checksum = self.calculate_checksum();
     }
 }
-impl Message for ScheduleRequest
-{
-    fn calculate_checksum(&self) -> u8
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u16::<BigEndian>(self.network_id).unwrap();
-        buffer.write_u16::<BigEndian>(self.channel_id).unwrap();
-        buffer.write_all(&self.schedule).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::frame::Frame;
+    use crate::messages::round_trip;
+
+    #[test]
+    fn fixed_requests_round_trip() {
+        round_trip(&BootRequest::new());
+        round_trip(&BootConfirmRequest::new());
+        round_trip(&UnlockRequest::new());
+        round_trip(&LockRequest::new());
     }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
-    {
-        if input.is_empty() || input.len() < 65 {
-            return Err(Err::Incomplete(Needed::new(65)));
-        }
-        let network_id = u16::from_be_bytes([input[4], input[5]]);
-        let channel_id = u16::from_be_bytes([input[6], input[7]]);
-        let mut request = ScheduleRequest::new(network_id, channel_id);
-        request.header = input[0];
-        request.command = u16::from_be_bytes([input[1], input[2]]);
-        request.payload_length = input[3];
-        request.schedule = input[8..64].to_vec();
-        request.checksum = input[64];
 
-        Ok((input, request))
+    #[test]
+    fn update_time_request_round_trips() {
+        let read = round_trip(&UpdateTimeRequest::new(0x1234, 0x5566_7788));
+        assert_eq!((read.network_id, read.time), (0x1234, 0x5566_7788));
+    }
+
+    #[test]
+    fn handshake_request_round_trips() {
+        assert_eq!(round_trip(&HandshakeRequest::new(0x1234)).network_id, 0x1234);
+    }
+
+    #[test]
+    fn samples_request_round_trips() {
+        let read = round_trip(&SamplesRequest::new(0x1234, 1));
+        assert_eq!((read.network_id, read.channel_id), (0x1234, 1));
+    }
+
+    #[test]
+    fn schedule_request_round_trips() {
+        let mut request = ScheduleRequest::new(0x1234, 1);
+        request.always_off();
+        assert_eq!(round_trip(&request).schedule, request.schedule);
+    }
+
+    #[test]
+    fn schedule_request_length_matches_its_payload() {
+        let mut request = ScheduleRequest::new(0x1234, 1);
+        request.always_on();
+        let bytes = request.as_bytes();
+        assert_eq!(bytes.len(), 5 + 59);
+        assert_eq!(bytes[3], 59);
+
+        let (rest, read) = ScheduleRequest::read(&bytes).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(read.network_id, 0x1234);
+        assert_eq!(read.channel_id, 1);
+        assert_eq!(read.schedule, request.schedule);
+        assert_eq!(read.as_bytes(), bytes);
+        // A decoder sees exactly one frame
+        let (rest, frame) = Frame::read(&bytes).unwrap();
+        assert!(rest.is_empty() && frame.checksum_valid());
     }
 }
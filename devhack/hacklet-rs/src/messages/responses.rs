@@ -1,22 +1,30 @@
-use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use alloc::vec::Vec;
+use core::slice::ChunksExact;
 use nom::{
     self,
-    Err, IResult, Needed,
+    bytes::streaming::take,
+    combinator::{complete, recognize, verify},
+    number::streaming::be_u8,
+    sequence::pair,
+    IResult,
 };
-use std::io::Write;
-use super::Message;
+use hacklet_derive::HackletMessage;
+use super::{frame::Frame, frame_header, Message};
 
 
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0x4084, payload_length = 22)]
+#[non_exhaustive]
 pub struct BootResponse
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
+    #[hacklet(len = 12)]
     pub data: Vec<u8>,
     pub device_id: u64,
     pub data2: u16,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl BootResponse
 {
@@ -34,60 +42,72 @@ impl BootResponse
         resp.checksum = resp.calculate_checksum();
         resp
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_all(&self.data).unwrap();
-        buffer.write_u64::<BigEndian>(self.device_id).unwrap();
-        buffer.write_u16::<BigEndian>(self.data2).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
 }
 
-impl Message for BootResponse
+
+// A boot response borrowed from the input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BootView<'a>
 {
-    fn calculate_checksum(&self) -> u8
+    frame: &'a [u8],
+}
+impl<'a> BootView<'a>
+{
+    pub fn read(input: &'a [u8]) -> IResult<&'a [u8], Self>
     {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_all(&self.data).unwrap();
-        buffer.write_u64::<BigEndian>(self.device_id).unwrap();
-        buffer.write_u16::<BigEndian>(self.data2).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
+        // Payload and checksum
+        let (rest, frame) = recognize(pair(frame_header(0x4084, Some(22)), take(23usize)))(input)?;
+        Ok((rest, BootView { frame }))
     }
-
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
+    pub fn header(&self) -> u8
     {
-        if input.is_empty() || input.len() < 27 {
-            return Err(Err::Incomplete(Needed::new(27)));
-        }
-        let data = input[4..16].to_vec();
-        let device_id = u64::from_be_bytes(input[16..24].try_into().unwrap());
-        let data2 = u16::from_be_bytes([input[24], input[25]]);
-        let mut response = BootResponse::new(data, device_id, data2);
-        response.header = input[0];
-        response.command = u16::from_be_bytes([input[1], input[2]]);
-        response.payload_length = input[3];
-        response.checksum = input[26];
-
-        Ok((input, response))
+        self.frame[0]
+    }
+    pub fn command(&self) -> u16
+    {
+        u16::from_be_bytes([self.frame[1], self.frame[2]])
+    }
+    pub fn payload_length(&self) -> u8
+    {
+        self.frame[3]
+    }
+    pub fn data(&self) -> &'a [u8]
+    {
+        &self.frame[4..16]
+    }
+    pub fn device_id(&self) -> u64
+    {
+        u64::from_be_bytes(self.frame[16..24].try_into().unwrap())
+    }
+    pub fn data2(&self) -> u16
+    {
+        u16::from_be_bytes([self.frame[24], self.frame[25]])
+    }
+    pub fn checksum(&self) -> u8
+    {
+        self.frame[26]
+    }
+    pub fn to_response(&self) -> BootResponse
+    {
+        let mut response = BootResponse::new(self.data().to_vec(), self.device_id(), self.data2());
+        response.header = self.header();
+        response.command = self.command();
+        response.payload_length = self.payload_length();
+        response.checksum = self.checksum();
+        response
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0x4080, payload_length = 1)]
+#[non_exhaustive]
 pub struct BootConfirmResponse
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
     pub data: u8,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl BootConfirmResponse
 {
@@ -103,53 +123,27 @@ impl BootConfirmResponse
         resp.checksum = resp.calculate_checksum();
         resp
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
 }
-impl Message for BootConfirmResponse
+impl Default for BootConfirmResponse
 {
-    fn calculate_checksum(&self) -> u8
+    fn default() -> Self
     {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
-    }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
-    {
-        if input.is_empty() || input.len() < 6 {
-            return Err(Err::Incomplete(Needed::new(6)));
-        }
-        let mut response = BootConfirmResponse::new();
-        response.header = input[0];
-        response.command = u16::from_be_bytes([input[1], input[2]]);
-        response.payload_length = input[3];
-        response.data = input[4];
-        response.checksum = input[5];
-
-        Ok((input, response))
+        BootConfirmResponse::new()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0xA013, payload_length = 11)]
+#[non_exhaustive]
 pub struct BroadcastResponse
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
     pub network_id: u16,
     pub device_id: u64,
     pub data: u8,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl BroadcastResponse
 {
@@ -167,57 +161,18 @@ impl BroadcastResponse
         resp.checksum = resp.calculate_checksum();
         resp
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u16::<BigEndian>(self.network_id).unwrap();
-        buffer.write_u64::<BigEndian>(self.device_id).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
 }
-impl Message for BroadcastResponse
-{
-    fn calculate_checksum(&self) -> u8
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u16::<BigEndian>(self.network_id).unwrap();
-        buffer.write_u64::<BigEndian>(self.device_id).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
-    }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
-    {
-        if input.is_empty() || input.len() < 16 {
-            return Err(Err::Incomplete(Needed::new(16)));
-        }
-        let network_id = u16::from_be_bytes([input[4], input[5]]);
-        let device_id = u64::from_be_bytes(input[6..14].try_into().unwrap());
-        let data = input[14];
-        let mut response = BroadcastResponse::new(network_id, device_id, data);
-        response.header = input[0];
-        response.command = u16::from_be_bytes([input[1], input[2]]);
-        response.payload_length = input[3];
-        response.checksum = input[15];
 
-        Ok((input, response))
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0xA0F9, payload_length = 1)]
+#[non_exhaustive]
 pub struct LockResponse
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
     pub data: u8,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl LockResponse
 {
@@ -233,54 +188,25 @@ impl LockResponse
         resp.checksum = resp.calculate_checksum();
         resp
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
 }
-impl Message for LockResponse
+impl Default for LockResponse
 {
-    fn calculate_checksum(&self) -> u8
+    fn default() -> Self
     {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
-    }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
-    {
-        if input.is_empty() || input.len() < 6 {
-            return Err(Err::Incomplete(Needed::new(6)));
-        }
-        let mut response = LockResponse::new();
-        response.header = input[0];
-        response.command = u16::from_be_bytes([input[1], input[2]]);
-        response.payload_length = input[3];
-        response.data = input[4];
-        response.checksum = input[5];
-
-        Ok((
-            input,
-            response,
-        ))
+        LockResponse::new()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0x4022, payload_length = 1)]
+#[non_exhaustive]
 pub struct UpdateTimeAckResponse
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
     pub data: u8,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl UpdateTimeAckResponse
 {
@@ -296,55 +222,26 @@ impl UpdateTimeAckResponse
         resp.checksum = resp.calculate_checksum();
         resp
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
 }
-impl Message for UpdateTimeAckResponse
+impl Default for UpdateTimeAckResponse
 {
-    fn calculate_checksum(&self) -> u8
+    fn default() -> Self
     {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
-    }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
-    {
-        if input.is_empty() || input.len() < 6 {
-            return Err(Err::Incomplete(Needed::new(6)));
-        }
-        let mut response = UpdateTimeAckResponse::new();
-        response.header = input[0];
-        response.command = u16::from_be_bytes([input[1], input[2]]);
-        response.payload_length = input[3];
-        response.data = input[4];
-        response.checksum = input[5];
-
-        Ok((
-            input,
-            response,
-        ))
+        UpdateTimeAckResponse::new()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0x40A2, payload_length = 3)]
+#[non_exhaustive]
 pub struct UpdateTimeResponse
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
     pub network_id: u16,
     pub data: u8,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl UpdateTimeResponse
 {
@@ -361,57 +258,18 @@ impl UpdateTimeResponse
         resp.checksum = resp.calculate_checksum();
         resp
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u16::<BigEndian>(self.network_id).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
-}
-impl Message for UpdateTimeResponse
-{
-    fn calculate_checksum(&self) -> u8
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u16::<BigEndian>(self.network_id).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
-    }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
-    {
-        if input.is_empty() || input.len() < 8 {
-            return Err(Err::Incomplete(Needed::new(8)));
-        }
-        let network_id = u16::from_be_bytes([input[4], input[5]]);
-        let mut response = UpdateTimeResponse::new(network_id);
-        response.header = input[0];
-        response.command = u16::from_be_bytes([input[1], input[2]]);
-        response.payload_length = input[3];
-        response.data = input[6];
-        response.checksum = input[7];
-
-        Ok((
-            input,
-            response,
-        ))
-    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0x4003, payload_length = 1)]
+#[non_exhaustive]
 pub struct HandshakeResponse
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
     pub data: u8,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl HandshakeResponse
 {
@@ -427,54 +285,25 @@ impl HandshakeResponse
         resp.checksum = resp.calculate_checksum();
         resp
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
 }
-impl Message for HandshakeResponse
+impl Default for HandshakeResponse
 {
-    fn calculate_checksum(&self) -> u8
+    fn default() -> Self
     {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
-    }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
-    {
-        if input.is_empty() || input.len() < 6 {
-            return Err(Err::Incomplete(Needed::new(6)));
-        }
-        let mut response = HandshakeResponse::new();
-        response.header = input[0];
-        response.command = u16::from_be_bytes([input[1], input[2]]);
-        response.payload_length = input[3];
-        response.data = input[4];
-        response.checksum = input[5];
-
-        Ok((
-            input,
-            response,
-        ))
+        HandshakeResponse::new()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0x4024, payload_length = 1)]
+#[non_exhaustive]
 pub struct AckResponse
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
     pub data: u8,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl AckResponse
 {
@@ -490,76 +319,51 @@ impl AckResponse
         resp.checksum = resp.calculate_checksum();
         resp
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
 }
-impl Message for AckResponse
+impl Default for AckResponse
 {
-    fn calculate_checksum(&self) -> u8
+    fn default() -> Self
     {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
-    }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
-    {
-        if input.is_empty() || input.len() < 6 {
-            return Err(Err::Incomplete(Needed::new(6)));
-        }
-        let mut response = AckResponse::new();
-        response.header = input[0];
-        response.command = u16::from_be_bytes([input[1], input[2]]);
-        response.payload_length = input[3];
-        response.data = input[4];
-        response.checksum = input[5];
-
-        Ok((
-            input,
-            response,
-        ))
+        AckResponse::new()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0x40A4)]
+#[non_exhaustive]
 pub struct SamplesResponse
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
     pub network_id: u16,
     pub channel_id: u16,
     pub data: u16,
-    pub time: u32, // little-endian
+    #[hacklet(le)]
+    pub time: u32,
     pub sample_count: u8,
-    pub stored_sample_count: u32, // 3-byte, little-endian
+    #[hacklet(le, bytes = 3)]
+    pub stored_sample_count: u32,
+    #[hacklet(le, count = sample_count)]
     pub samples: Vec<u16>,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl SamplesResponse {
+    // The sample count and payload length follow from `samples`, at most 120
+    // fit in one frame
     pub fn new(
-        payload_length: u8, 
         network_id: u16, 
         channel_id: u16, 
         data: u16, 
-        sample_count: u8, 
         time: u32, 
         stored_sample_count : u32, 
         samples: Vec<u16>
     ) -> Self {
+        let sample_count = samples.len() as u8;
         let mut req = SamplesResponse {
             header: 0x02,           // Default header
             command: 0x40A4,        // Default command
-            payload_length,      // TODO: payload length
+            payload_length: 14 + sample_count * 2,
             network_id,
             channel_id,
             data,
@@ -572,101 +376,117 @@ impl SamplesResponse {
         req.checksum = req.calculate_checksum();
         req
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u16::<BigEndian>(self.network_id).unwrap();
-        buffer.write_u16::<BigEndian>(self.channel_id).unwrap();
-        buffer.write_u16::<BigEndian>(self.data).unwrap();
-        buffer.write_u32::<LittleEndian>(self.time).unwrap();
-        buffer.write_u8(self.sample_count).unwrap();
-        buffer
-            .write_u8((self.stored_sample_count & 0xFF) as u8)
-            .unwrap();
-        buffer
-            .write_u8(((self.stored_sample_count >> 8) & 0xFF) as u8)
-            .unwrap();
-        buffer
-            .write_u8(((self.stored_sample_count >> 16) & 0xFF) as u8)
-            .unwrap();
-        for sample in &self.samples {
-            buffer.write_u16::<LittleEndian>(*sample).unwrap();
-        }
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
-}
-impl Message for SamplesResponse
-{
-    fn calculate_checksum(&self) -> u8
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u16::<BigEndian>(self.network_id).unwrap();
-        buffer.write_u16::<BigEndian>(self.channel_id).unwrap();
-        buffer.write_u16::<BigEndian>(self.data).unwrap();
-        buffer.write_u32::<LittleEndian>(self.time).unwrap();
-        buffer.write_u8(self.sample_count).unwrap();
-        buffer
-            .write_u8((self.stored_sample_count & 0xFF) as u8)
-            .unwrap();
-        buffer
-            .write_u8(((self.stored_sample_count >> 8) & 0xFF) as u8)
-            .unwrap();
-        buffer
-            .write_u8(((self.stored_sample_count >> 16) & 0xFF) as u8)
-            .unwrap();
-        for sample in &self.samples {
-            buffer.write_u16::<LittleEndian>(*sample).unwrap();
-        }
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
+}
+
+// A samples response borrowed from the input, the samples are decoded as
+// they are iterated instead of being copied into a `Vec`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplesView<'a>
+{
+    frame: &'a [u8],
+}
+impl<'a> SamplesView<'a>
+{
+    pub fn read(input: &'a [u8]) -> IResult<&'a [u8], Self>
+    {
+        let (rest, (_, _, payload_length)) = frame_header(0x40A4, None)(input)?;
+        // Network, channel, data and time come before the sample count
+        let (rest, _) = take(10usize)(rest)?;
+        // The samples fill the payload after the 14 bytes of fixed fields
+        let (rest, sample_count) = verify(be_u8, |&count| 14 + count as usize * 2 == payload_length as usize)(rest)?;
+        // Stored sample count, the samples and the checksum
+        let (rest, _) = take(3 + sample_count as usize * 2 + 1)(rest)?;
+        let length = input.len() - rest.len();
+        Ok((rest, SamplesView { frame: &input[..length] }))
     }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
+    pub fn header(&self) -> u8
     {
-        if input.is_empty() || input.len() < 18 {
-            return Err(Err::Incomplete(Needed::new(18)));
-        }
-        let payload_length = input[3];
-        let network_id = u16::from_be_bytes([input[4], input[5]]);
-        let channel_id = u16::from_be_bytes([input[6], input[7]]);
-        let data = u16::from_be_bytes([input[8], input[9]]);
-        let time = u32::from_le_bytes([input[10], input[11], input[12], input[13]]);
-        let sample_count = input[14];
-        let stored_sample_count = u32::from_le_bytes([0, input[15], input[16], input[17]]);
-        let mut samples = Vec::new();
-        let sample_bytes_start = 18;
-        let sample_bytes_end = sample_bytes_start + (sample_count as usize) * 2;
-        if input.len() < sample_bytes_end + 1 {
-            return Err(Err::Incomplete(Needed::new(sample_bytes_end + 1)));
-        }
-        for i in (sample_bytes_start..sample_bytes_end).step_by(2) {
-            let sample = u16::from_le_bytes([input[i], input[i + 1]]);
-            samples.push(sample);
-        }
-        let mut response = SamplesResponse::new(payload_length, network_id, channel_id, data, sample_count, time, stored_sample_count, samples);
-        response.header = input[0];
-        response.command = u16::from_be_bytes([input[1], input[2]]);
-        response.checksum = input[sample_bytes_end];
+        self.frame[0]
+    }
+    pub fn command(&self) -> u16
+    {
+        u16::from_be_bytes([self.frame[1], self.frame[2]])
+    }
+    pub fn payload_length(&self) -> u8
+    {
+        self.frame[3]
+    }
+    pub fn network_id(&self) -> u16
+    {
+        u16::from_be_bytes([self.frame[4], self.frame[5]])
+    }
+    pub fn channel_id(&self) -> u16
+    {
+        u16::from_be_bytes([self.frame[6], self.frame[7]])
+    }
+    pub fn data(&self) -> u16
+    {
+        u16::from_be_bytes([self.frame[8], self.frame[9]])
+    }
+    pub fn time(&self) -> u32
+    {
+        u32::from_le_bytes([self.frame[10], self.frame[11], self.frame[12], self.frame[13]])
+    }
+    pub fn sample_count(&self) -> u8
+    {
+        self.frame[14]
+    }
+    pub fn stored_sample_count(&self) -> u32
+    {
+        u32::from_le_bytes([self.frame[15], self.frame[16], self.frame[17], 0])
+    }
+    pub fn samples(&self) -> Samples<'a>
+    {
+        Samples(self.frame[18..self.frame.len() - 1].chunks_exact(2))
+    }
+    pub fn checksum(&self) -> u8
+    {
+        self.frame[self.frame.len() - 1]
+    }
+    pub fn to_response(&self) -> SamplesResponse
+    {
+        let mut response = SamplesResponse::new(
+            self.network_id(),
+            self.channel_id(),
+            self.data(),
+            self.time(),
+            self.stored_sample_count(),
+            self.samples().collect(),
+        );
+        response.header = self.header();
+        response.command = self.command();
+        response.checksum = self.checksum();
+        response
+    }
+}
 
-        Ok((
-            input,
-            response,
-        ))
+#[derive(Debug, Clone)]
+pub struct Samples<'a>(ChunksExact<'a, u8>);
+impl Iterator for Samples<'_>
+{
+    type Item = u16;
+
+    fn next(&mut self) -> Option<u16>
+    {
+        self.0.next().map(|sample| u16::from_le_bytes([sample[0], sample[1]]))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>)
+    {
+        self.0.size_hint()
     }
 }
+impl ExactSizeIterator for Samples<'_> {}
 
-#[derive(Debug)]
+#[derive(Debug, HackletMessage)]
+#[hacklet(command = 0x4023, payload_length = 1)]
+#[non_exhaustive]
 pub struct ScheduleResponse
 {
-    pub header: u8,
-    pub command: u16,
-    pub payload_length: u8,
+    header: u8,
+    command: u16,
+    payload_length: u8,
     pub data: u8,
-    pub checksum: u8,
+    checksum: u8,
 }
 impl ScheduleResponse
 {
@@ -682,43 +502,122 @@ impl ScheduleResponse
         resp.checksum = resp.calculate_checksum();
         resp
     }
-    pub fn as_bytes(&self) -> Vec<u8>
-    {
-        let mut buffer = Vec::new();
-        buffer.write_u8(self.header).unwrap();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.write_u8(self.checksum).unwrap();
-        buffer
-    }
 }
-impl Message for ScheduleResponse
+impl Default for ScheduleResponse
 {
-    fn calculate_checksum(&self) -> u8
+    fn default() -> Self
     {
-        let mut buffer = Vec::new();
-        buffer.write_u16::<BigEndian>(self.command).unwrap();
-        buffer.write_u8(self.payload_length).unwrap();
-        buffer.write_u8(self.data).unwrap();
-        buffer.iter().fold(0, |acc, &x| acc ^ x)
+        ScheduleResponse::new()
     }
-    fn read(input: &[u8]) -> IResult<&[u8], Self>
-    {
-        if input.is_empty() || input.len() < 6 {
-            return Err(Err::Incomplete(Needed::new(6)));
-        }
-        let mut response = ScheduleResponse::new();
-        response.header = input[0];
-        response.command = u16::from_be_bytes([input[1], input[2]]);
-        response.payload_length = input[3];
-        response.data = input[4];
-        response.checksum = input[5];
+}
+
 
-        Ok((
-            input,
-            response,
-        ))
+// Any frame the dongle sends, for callers that do not know what comes next.
+// Commands without a response type here are kept as `Unknown`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Response
+{
+    Boot(BootResponse),
+    BootConfirm(BootConfirmResponse),
+    Broadcast(BroadcastResponse),
+    Lock(LockResponse),
+    UpdateTimeAck(UpdateTimeAckResponse),
+    UpdateTime(UpdateTimeResponse),
+    Handshake(HandshakeResponse),
+    Ack(AckResponse),
+    Samples(SamplesResponse),
+    Schedule(ScheduleResponse),
+    Unknown { command: u16, payload: Vec<u8> },
+}
+impl Response
+{
+    // Consumes one frame and parses it as the response its command belongs to.
+    // A known command whose frame does not have the expected layout is kept as
+    // `Unknown` too, so one odd frame does not stop a stream.
+    pub fn read(input: &[u8]) -> IResult<&[u8], Self>
+    {
+        let (rest, frame) = Frame::read(input)?;
+        let bytes = &input[..input.len() - rest.len()];
+        let response = match frame.command {
+            0x4084 => parse(bytes).map(Response::Boot),
+            0x4080 => parse(bytes).map(Response::BootConfirm),
+            0xA013 => parse(bytes).map(Response::Broadcast),
+            0xA0F9 => parse(bytes).map(Response::Lock),
+            0x4022 => parse(bytes).map(Response::UpdateTimeAck),
+            0x40A2 => parse(bytes).map(Response::UpdateTime),
+            0x4003 => parse(bytes).map(Response::Handshake),
+            0x4024 => parse(bytes).map(Response::Ack),
+            0x40A4 => parse(bytes).map(Response::Samples),
+            0x4023 => parse(bytes).map(Response::Schedule),
+            _ => None,
+        };
+        let response = response.unwrap_or(Response::Unknown { command: frame.command, payload: frame.payload });
+        Ok((rest, response))
+    }
+    pub fn command(&self) -> u16
+    {
+        match self {
+            Response::Boot(response) => response.command,
+            Response::BootConfirm(response) => response.command,
+            Response::Broadcast(response) => response.command,
+            Response::Lock(response) => response.command,
+            Response::UpdateTimeAck(response) => response.command,
+            Response::UpdateTime(response) => response.command,
+            Response::Handshake(response) => response.command,
+            Response::Ack(response) => response.command,
+            Response::Samples(response) => response.command,
+            Response::Schedule(response) => response.command,
+            Response::Unknown { command, .. } => *command,
+        }
     }
 }
 
+// The frame is already complete, running out of bytes means it is malformed
+fn parse<M: Message>(bytes: &[u8]) -> Option<M>
+{
+    complete(M::read)(bytes).ok().map(|(_, message)| message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use crate::messages::round_trip;
+
+    #[test]
+    fn fixed_responses_round_trip() {
+        round_trip(&BootConfirmResponse::new());
+        round_trip(&LockResponse::new());
+        round_trip(&UpdateTimeAckResponse::new());
+        round_trip(&HandshakeResponse::new());
+        round_trip(&AckResponse::new());
+        round_trip(&ScheduleResponse::new());
+    }
+
+    #[test]
+    fn boot_response_round_trips() {
+        let read = round_trip(&BootResponse::new(vec![0; 12], 0x0011_2233_4455_6677, 0x8899));
+        assert_eq!((read.device_id, read.data2), (0x0011_2233_4455_6677, 0x8899));
+    }
+
+    #[test]
+    fn broadcast_response_round_trips() {
+        let read = round_trip(&BroadcastResponse::new(0x1234, 0x0011_2233_4455_6677, 0x01));
+        assert_eq!((read.network_id, read.device_id), (0x1234, 0x0011_2233_4455_6677));
+    }
+
+    #[test]
+    fn update_time_response_round_trips() {
+        assert_eq!(round_trip(&UpdateTimeResponse::new(0x1234)).network_id, 0x1234);
+    }
+
+    #[test]
+    fn samples_response_round_trips() {
+        let samples = vec![0x0102, 0x0304];
+        let response = SamplesResponse::new(0x1234, 1, 0, 0x5566_7788, 0x00_0102, samples.clone());
+        let read = round_trip(&response);
+        assert_eq!((read.time, read.stored_sample_count), (0x5566_7788, 0x00_0102));
+        assert_eq!(read.samples, samples);
+    }
+}
@@ -0,0 +1,227 @@
+use std::io::{self, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::{
+    capture::{Capture, Direction},
+    decode::{self, Decoded},
+};
+
+// pcapng export - one packet per decoded frame, so long sessions can be
+// browsed in Wireshark and friends. There is no registered link type for the
+// dongle's serial protocol, packets use LINKTYPE_USER0 and carry the frame
+// bytes exactly as they were on the wire. The direction is stored in the
+// packet's epb_flags, filter on it with `frame.p2p_dir`.
+//
+// Timestamps are in microseconds (the default if_tsresol), taken from the
+// capture's wall clock start plus each frame's offset.
+
+pub const LINKTYPE_USER0: u16 = 147;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+
+const EPB_FLAGS_INBOUND: u32 = 0b01;
+const EPB_FLAGS_OUTBOUND: u32 = 0b10;
+
+pub struct PcapngWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    // Writes the section header and the single interface all packets belong to
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        write_block(&mut writer, SECTION_HEADER_BLOCK, |body| {
+            body.write_u32::<LittleEndian>(BYTE_ORDER_MAGIC)?;
+            body.write_u16::<LittleEndian>(1)?;
+            body.write_u16::<LittleEndian>(0)?;
+            // Section length unknown
+            body.write_i64::<LittleEndian>(-1)
+        })?;
+        write_block(&mut writer, INTERFACE_DESCRIPTION_BLOCK, |body| {
+            body.write_u16::<LittleEndian>(LINKTYPE_USER0)?;
+            body.write_u16::<LittleEndian>(0)?;
+            // No snapshot length limit
+            body.write_u32::<LittleEndian>(0)?;
+            write_option(body, OPT_IF_NAME, b"hacklet")?;
+            write_option(body, OPT_ENDOFOPT, &[])
+        })?;
+        Ok(PcapngWriter { writer })
+    }
+
+    // `timestamp` is microseconds since the Unix epoch
+    pub fn write_packet(&mut self, timestamp: u64, direction: Option<Direction>, data: &[u8]) -> io::Result<()> {
+        write_block(&mut self.writer, ENHANCED_PACKET_BLOCK, |body| {
+            body.write_u32::<LittleEndian>(0)?;
+            body.write_u32::<LittleEndian>((timestamp >> 32) as u32)?;
+            body.write_u32::<LittleEndian>(timestamp as u32)?;
+            body.write_u32::<LittleEndian>(data.len() as u32)?;
+            body.write_u32::<LittleEndian>(data.len() as u32)?;
+            body.extend_from_slice(data);
+            pad(body);
+            // Frames sent to the dongle are outbound, its responses inbound
+            let flags = match direction {
+                Some(Direction::Tx) => Some(EPB_FLAGS_OUTBOUND),
+                Some(Direction::Rx) => Some(EPB_FLAGS_INBOUND),
+                None => None,
+            };
+            if let Some(flags) = flags {
+                write_option(body, OPT_EPB_FLAGS, &flags.to_le_bytes())?;
+                write_option(body, OPT_ENDOFOPT, &[])?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// Writes every frame of a capture, including bytes that did not parse as one
+// so nothing from the session goes missing. Returns the number of packets.
+pub fn export<W: Write>(capture: &Capture, writer: W) -> io::Result<usize> {
+    let mut pcapng = PcapngWriter::new(writer)?;
+    let frames = decode::decode_capture(capture);
+    for frame in &frames {
+        let data = match &frame.decoded {
+            Decoded::Frame(frame) => frame.as_bytes(),
+            Decoded::Garbage(bytes) => bytes.clone(),
+        };
+        let timestamp = capture.start + frame.time.unwrap_or(0);
+        pcapng.write_packet(timestamp, frame.direction, &data)?;
+    }
+    pcapng.into_inner()?;
+    Ok(frames.len())
+}
+
+// Every block is framed by its type and total length, with the length repeated
+// at the end so tools can walk the file backwards
+fn write_block<W, F>(writer: &mut W, block_type: u32, fill: F) -> io::Result<()>
+where
+    W: Write,
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+{
+    let mut body = Vec::new();
+    fill(&mut body)?;
+    let total_length = (body.len() + 12) as u32;
+
+    writer.write_u32::<LittleEndian>(block_type)?;
+    writer.write_u32::<LittleEndian>(total_length)?;
+    writer.write_all(&body)?;
+    writer.write_u32::<LittleEndian>(total_length)
+}
+
+fn write_option(body: &mut Vec<u8>, code: u16, value: &[u8]) -> io::Result<()> {
+    body.write_u16::<LittleEndian>(code)?;
+    body.write_u16::<LittleEndian>(value.len() as u16)?;
+    body.extend_from_slice(value);
+    pad(body);
+    Ok(())
+}
+
+// Block contents are aligned to 32 bits
+fn pad(body: &mut Vec<u8>) {
+    while body.len() % 4 != 0 {
+        body.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::CaptureEvent;
+    use crate::messages::{
+        requests::{BootConfirmRequest, BootRequest, LockRequest},
+        responses::{BootConfirmResponse, BootResponse, LockResponse},
+    };
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    // Returns (type, body) for every block, checking the trailing lengths
+    fn blocks(mut bytes: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        while !bytes.is_empty() {
+            let length = u32_at(bytes, 4) as usize;
+            assert_eq!(length % 4, 0);
+            assert_eq!(u32_at(bytes, length - 4) as usize, length);
+            blocks.push((u32_at(bytes, 0), bytes[8..length - 4].to_vec()));
+            bytes = &bytes[length..];
+        }
+        blocks
+    }
+
+    #[test]
+    fn writes_one_packet_per_frame_with_direction() {
+        let request = BootRequest::new().as_bytes();
+        let capture = Capture {
+            start: 1_700_000_000_000_000,
+            events: vec![
+                CaptureEvent { time: 10, direction: Direction::Tx, data: request.clone() },
+                CaptureEvent { time: 20, direction: Direction::Rx, data: vec![0x02, 0x40, 0x24] },
+                CaptureEvent { time: 30, direction: Direction::Rx, data: vec![0x01, 0x00, 0x65] },
+            ],
+        };
+
+        let mut output = Vec::new();
+        assert_eq!(export(&capture, &mut output).unwrap(), 2);
+
+        let blocks = blocks(&output);
+        let types: Vec<u32> = blocks.iter().map(|(block_type, _)| *block_type).collect();
+        assert_eq!(types, vec![SECTION_HEADER_BLOCK, INTERFACE_DESCRIPTION_BLOCK, ENHANCED_PACKET_BLOCK, ENHANCED_PACKET_BLOCK]);
+        assert_eq!(u32_at(&blocks[0].1, 0), BYTE_ORDER_MAGIC);
+        assert_eq!(u16::from_le_bytes([blocks[1].1[0], blocks[1].1[1]]), LINKTYPE_USER0);
+
+        let tx = &blocks[2].1;
+        let timestamp = ((u32_at(tx, 4) as u64) << 32) | u32_at(tx, 8) as u64;
+        assert_eq!(timestamp, 1_700_000_000_000_010);
+        assert_eq!(u32_at(tx, 12) as usize, request.len());
+        assert_eq!(&tx[20..20 + request.len()], &request[..]);
+        // epb_flags value follows the padded packet data and option header
+        assert_eq!(u32_at(tx, 32), EPB_FLAGS_OUTBOUND);
+
+        let rx = &blocks[3].1;
+        assert_eq!(&rx[20..26], &[0x02, 0x40, 0x24, 0x01, 0x00, 0x65]);
+        assert_eq!(u32_at(rx, 32), EPB_FLAGS_INBOUND);
+    }
+
+    #[test]
+    fn exports_a_boot_sequence_frame_by_frame() {
+        let chunks = vec![
+            (Direction::Tx, BootRequest::new().as_bytes()),
+            (Direction::Rx, BootResponse::new((1..=12).collect(), 0x0123_4567_89ab_cdef, 0x0506).as_bytes()),
+            (Direction::Tx, BootConfirmRequest::new().as_bytes()),
+            (Direction::Rx, BootConfirmResponse::new().as_bytes()),
+            (Direction::Tx, LockRequest::new().as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+        ];
+        let capture = Capture {
+            start: 0,
+            events: chunks
+                .iter()
+                .enumerate()
+                .map(|(i, (direction, data))| CaptureEvent { time: i as u64, direction: *direction, data: data.clone() })
+                .collect(),
+        };
+
+        let mut output = Vec::new();
+        assert_eq!(export(&capture, &mut output).unwrap(), chunks.len());
+
+        let packets = &blocks(&output)[2..];
+        assert_eq!(packets.len(), chunks.len());
+        for ((block_type, body), (_, data)) in packets.iter().zip(&chunks) {
+            assert_eq!(*block_type, ENHANCED_PACKET_BLOCK);
+            assert_eq!(u32_at(body, 12) as usize, data.len());
+            assert_eq!(&body[20..20 + data.len()], &data[..]);
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use std::{
+    env,
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+// Device registry - gives networks found by `commission` a name so commands
+// can refer to `office` instead of `0x1234`, and collects sockets into groups
+// and scenes.
+//
+// The registry is a plain text file with one entry per line, blank lines and
+// lines starting with `#` are ignored. Sockets are written `<network>/<socket>`
+// and scene actions `<socket or group>=on|off`:
+//
+//     network office 0x1234 dongle 0123456789abcdef
+//     network lab 0xbeef
+//     group desk office/0 office/1
+//     scene night desk=off lab/2=on
+//
+// A network may name the dongle it was commissioned with by the id the dongle
+// reports when it boots.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Network {
+    pub name: String,
+    pub network_id: u16,
+    // Device id of the dongle the network was paired with, if known
+    pub dongle: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Socket {
+    pub network_id: u16,
+    pub socket_id: u16,
+}
+
+impl std::fmt::Display for Socket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "0x{:04x}/{}", self.network_id, self.socket_id)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub name: String,
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scene {
+    pub name: String,
+    pub actions: Vec<(String, bool)>,
+}
+
+#[derive(Debug)]
+pub struct Registry {
+    path: PathBuf,
+    networks: Vec<Network>,
+    groups: Vec<Group>,
+    scenes: Vec<Scene>,
+}
+
+impl Registry {
+    // $HACKLET_REGISTRY if set, otherwise ~/.hacklet/registry
+    pub fn default_path() -> PathBuf {
+        if let Some(path) = env::var_os("HACKLET_REGISTRY") {
+            return PathBuf::from(path);
+        }
+        let home = env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+        home.join(".hacklet").join("registry")
+    }
+
+    // Loads the registry, a missing file is an empty registry
+    pub fn load(path: &Path) -> io::Result<Registry> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Registry::parse(path, &contents),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Registry {
+                path: path.to_path_buf(),
+                networks: Vec::new(),
+                groups: Vec::new(),
+                scenes: Vec::new(),
+            }),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub(crate) fn parse(path: &Path, contents: &str) -> io::Result<Registry> {
+        let mut registry = Registry {
+            path: path.to_path_buf(),
+            networks: Vec::new(),
+            groups: Vec::new(),
+            scenes: Vec::new(),
+        };
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |reason: &str| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path.display(), index + 1, reason),
+                )
+            };
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["network", name, id, rest @ ..] => {
+                    let network_id = parse_network_id(id).ok_or_else(|| invalid("invalid network id"))?;
+                    let dongle = match rest {
+                        [] => None,
+                        ["dongle", dongle] => {
+                            Some(u64::from_str_radix(dongle, 16).map_err(|_| invalid("invalid dongle id"))?)
+                        }
+                        _ => return Err(invalid("unrecognised entry")),
+                    };
+                    registry.networks.push(Network {
+                        name: name.to_string(),
+                        network_id,
+                        dongle,
+                    });
+                }
+                ["group", name, members @ ..] if !members.is_empty() => {
+                    registry.groups.push(Group {
+                        name: name.to_string(),
+                        members: members.iter().map(|member| member.to_string()).collect(),
+                    });
+                }
+                ["scene", name, actions @ ..] if !actions.is_empty() => {
+                    let actions = actions
+                        .iter()
+                        .map(|action| parse_action(action))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| invalid("invalid scene action, expected <socket or group>=on|off"))?;
+                    registry.scenes.push(Scene {
+                        name: name.to_string(),
+                        actions,
+                    });
+                }
+                _ => return Err(invalid("unrecognised entry")),
+            }
+        }
+
+        Ok(registry)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, self.to_string())
+    }
+
+    pub fn networks(&self) -> &[Network] {
+        &self.networks
+    }
+
+    // Looks a network up by name, falling back to a literal id such as 0x1234
+    pub fn network(&self, name_or_id: &str) -> Option<u16> {
+        self.networks
+            .iter()
+            .find(|network| network.name == name_or_id)
+            .map(|network| network.network_id)
+            .or_else(|| parse_network_id(name_or_id))
+    }
+
+    // Adds a network under `name`, returns false if it is already registered
+    pub fn add_network(&mut self, name: &str, network_id: u16) -> bool {
+        if self.networks.iter().any(|network| network.network_id == network_id) {
+            return false;
+        }
+        self.networks.push(Network {
+            name: name.to_string(),
+            network_id,
+            dongle: None,
+        });
+        true
+    }
+
+    // Records which dongle a network is paired with, returns false if the
+    // network isn't registered
+    pub fn set_dongle(&mut self, network_id: u16, dongle: u64) -> bool {
+        match self.networks.iter_mut().find(|network| network.network_id == network_id) {
+            Some(network) => {
+                network.dongle = Some(dongle);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Removes a network along with any group members and scene actions that
+    // refer to its sockets, groups and scenes left empty are removed as well
+    pub fn remove_network(&mut self, network_id: u16) -> Option<Network> {
+        let index = self.networks.iter().position(|network| network.network_id == network_id)?;
+
+        let on_network = |registry: &Registry, reference: &str| {
+            registry.socket(reference).is_some_and(|socket| socket.network_id == network_id)
+        };
+        let groups: Vec<Group> = self
+            .groups
+            .iter()
+            .map(|group| Group {
+                name: group.name.clone(),
+                members: group.members.iter().filter(|m| !on_network(self, m)).cloned().collect(),
+            })
+            .filter(|group| !group.members.is_empty())
+            .collect();
+        let scenes: Vec<Scene> = self
+            .scenes
+            .iter()
+            .map(|scene| Scene {
+                name: scene.name.clone(),
+                actions: scene
+                    .actions
+                    .iter()
+                    .filter(|(member, _)| {
+                        !on_network(self, member)
+                            && (self.group(member).is_none() || groups.iter().any(|group| &group.name == member))
+                    })
+                    .cloned()
+                    .collect(),
+            })
+            .filter(|scene| !scene.actions.is_empty())
+            .collect();
+
+        self.groups = groups;
+        self.scenes = scenes;
+        Some(self.networks.remove(index))
+    }
+
+    pub fn groups(&self) -> &[Group] {
+        &self.groups
+    }
+
+    pub fn group(&self, name: &str) -> Option<&Group> {
+        self.groups.iter().find(|group| group.name == name)
+    }
+
+    pub fn scenes(&self) -> &[Scene] {
+        &self.scenes
+    }
+
+    pub fn scene(&self, name: &str) -> Option<&Scene> {
+        self.scenes.iter().find(|scene| scene.name == name)
+    }
+
+    // Resolves a `<network>/<socket>` reference
+    pub fn socket(&self, reference: &str) -> Option<Socket> {
+        let (network, socket) = reference.split_once('/')?;
+        Some(Socket {
+            network_id: self.network(network)?,
+            socket_id: socket.parse().ok()?,
+        })
+    }
+
+    // Resolves a group name or a single socket reference
+    pub fn sockets(&self, member: &str) -> Option<Vec<Socket>> {
+        match self.group(member) {
+            Some(group) => group.members.iter().map(|member| self.socket(member)).collect(),
+            None => self.socket(member).map(|socket| vec![socket]),
+        }
+    }
+
+    // Expands a scene into the socket states it sets, in the order written
+    pub fn scene_actions(&self, name: &str) -> Result<Vec<(Socket, bool)>, String> {
+        let scene = self.scene(name).ok_or_else(|| format!("Unknown scene '{}'", name))?;
+
+        let mut actions = Vec::new();
+        for (member, state) in &scene.actions {
+            let sockets = self.sockets(member).ok_or_else(|| {
+                format!("Scene '{}' refers to unknown socket or group '{}'", name, member)
+            })?;
+            actions.extend(sockets.into_iter().map(|socket| (socket, *state)));
+        }
+        Ok(actions)
+    }
+}
+
+impl std::fmt::Display for Registry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "# hacklet device registry")?;
+        for network in &self.networks {
+            write!(f, "network {} 0x{:04x}", network.name, network.network_id)?;
+            if let Some(dongle) = network.dongle {
+                write!(f, " dongle {:016x}", dongle)?;
+            }
+            writeln!(f)?;
+        }
+        for group in &self.groups {
+            writeln!(f, "group {} {}", group.name, group.members.join(" "))?;
+        }
+        for scene in &self.scenes {
+            write!(f, "scene {}", scene.name)?;
+            for (member, state) in &scene.actions {
+                write!(f, " {}={}", member, if *state { "on" } else { "off" })?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// Parses a network id written in hex, with or without a 0x prefix
+pub fn parse_network_id(id: &str) -> Option<u16> {
+    let digits = id.strip_prefix("0x").or_else(|| id.strip_prefix("0X")).unwrap_or(id);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+fn parse_action(action: &str) -> Option<(String, bool)> {
+    let (member, state) = action.split_once('=')?;
+    let state = match state {
+        "on" => true,
+        "off" => false,
+        _ => return None,
+    };
+    Some((member.to_string(), state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> io::Result<Registry> {
+        Registry::parse(Path::new("registry"), contents)
+    }
+
+    #[test]
+    fn parses_networks_and_skips_comments() {
+        let registry = parse("# devices\n\nnetwork office 0x1234\nnetwork lab beef\n").unwrap();
+        assert_eq!(registry.network("office"), Some(0x1234));
+        assert_eq!(registry.network("lab"), Some(0xbeef));
+        assert_eq!(registry.network("0x0010"), Some(0x0010));
+        assert_eq!(registry.network("garage"), None);
+    }
+
+    #[test]
+    fn remembers_the_dongle_of_a_network() {
+        let mut registry = parse("network office 0x1234 dongle 0123456789abcdef\nnetwork lab 0xbeef\n").unwrap();
+        assert_eq!(registry.networks()[0].dongle, Some(0x0123_4567_89ab_cdef));
+        assert_eq!(registry.networks()[1].dongle, None);
+
+        assert!(registry.set_dongle(0xbeef, 0xfeed));
+        assert!(!registry.set_dongle(0x0010, 0xfeed));
+        assert!(registry.to_string().contains("network lab 0xbeef dongle 000000000000feed\n"));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let err = parse("network office 0x1234\nnetwork lab\n").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("registry:2"));
+    }
+
+    #[test]
+    fn removing_a_network_round_trips() {
+        let mut registry = parse("network office 0x1234\n").unwrap();
+        assert!(registry.add_network("lab", 0xbeef));
+        assert!(!registry.add_network("lab-again", 0xbeef));
+
+        let removed = registry.remove_network(0x1234).unwrap();
+        assert_eq!(removed.name, "office");
+        assert_eq!(registry.remove_network(0x1234), None);
+
+        let reparsed = parse(&registry.to_string()).unwrap();
+        assert_eq!(reparsed.networks(), registry.networks());
+    }
+
+    #[test]
+    fn expands_scenes_through_groups() {
+        let registry = parse(
+            "network office 0x1234\nnetwork lab 0xbeef\ngroup desk office/0 office/1\nscene night desk=off lab/2=on\n",
+        )
+        .unwrap();
+
+        let actions = registry.scene_actions("night").unwrap();
+        assert_eq!(
+            actions,
+            vec![
+                (Socket { network_id: 0x1234, socket_id: 0 }, false),
+                (Socket { network_id: 0x1234, socket_id: 1 }, false),
+                (Socket { network_id: 0xbeef, socket_id: 2 }, true),
+            ]
+        );
+        assert!(registry.scene_actions("morning").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_scene_actions() {
+        let err = parse("scene night desk=dim\n").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn removing_a_network_prunes_groups_and_scenes() {
+        let mut registry = parse(
+            "network office 0x1234\nnetwork lab 0xbeef\ngroup desk office/0 office/1\nscene night desk=off lab/2=on\n",
+        )
+        .unwrap();
+
+        registry.remove_network(0x1234).unwrap();
+        assert!(registry.group("desk").is_none());
+        assert_eq!(registry.scene("night").unwrap().actions, vec![("lab/2".to_string(), true)]);
+
+        let reparsed = parse(&registry.to_string()).unwrap();
+        assert_eq!(reparsed.scenes(), registry.scenes());
+    }
+}
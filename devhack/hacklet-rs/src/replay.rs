@@ -0,0 +1,218 @@
+use std::{cell::RefCell, collections::VecDeque, io, path::Path, rc::Rc};
+
+use crate::{
+    capture::{Capture, CaptureEvent, Direction},
+    messages::to_hex,
+    serial_connection::SerialConnection,
+    transport::Transport,
+};
+
+// Plays a capture back in place of the dongle. Received chunks are served in
+// the order they were recorded and every write has to match the next recorded
+// transmission, so a replay fails as soon as the code under test does
+// something the original session did not. Writes may arrive in smaller
+// pieces than they were recorded in (e.g. through `hacklet bridge`), they are
+// collected until a whole transmission can be compared.
+//
+// UpdateTimeRequest frames carry the current time, so for those the time and
+// checksum bytes are left out of the comparison.
+pub struct ReplayTransport {
+    events: VecDeque<CaptureEvent>,
+    // Bytes written that don't add up to the next transmission yet
+    pending: Vec<u8>,
+}
+
+impl ReplayTransport {
+    pub fn new(events: Vec<CaptureEvent>) -> Self {
+        ReplayTransport {
+            events: events.into(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(ReplayTransport::new(Capture::load(path)?.events))
+    }
+
+    // A session written out frame by frame, as tests do, with no timestamps
+    pub fn from_frames(frames: Vec<(Direction, Vec<u8>)>) -> Self {
+        ReplayTransport::new(
+            frames
+                .into_iter()
+                .map(|(direction, data)| CaptureEvent { time: 0, direction, data })
+                .collect(),
+        )
+    }
+
+    // Lets the replay be checked on after a connection has taken it
+    pub fn into_shared(self) -> SharedReplay {
+        SharedReplay(Rc::new(RefCell::new(self)))
+    }
+
+    // True once every recorded chunk has been played back
+    pub fn is_finished(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(data);
+
+        while !self.pending.is_empty() {
+            let event = match self.events.front() {
+                Some(event) if event.direction == Direction::Tx => event,
+                Some(event) => {
+                    return Err(diverged(format!(
+                        "sent [{}] but the capture has {} [{}] next",
+                        to_hex(&self.pending),
+                        event.direction,
+                        to_hex(&event.data)
+                    )))
+                }
+                // Like reading, the recorded session is over as if the dongle had gone
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("sent [{}] after the end of the capture", to_hex(&self.pending)),
+                    ))
+                }
+            };
+
+            let len = event.data.len().min(self.pending.len());
+            let matches = if len == event.data.len() {
+                same_transmission(&event.data, &self.pending[..len])
+            } else {
+                // Still waiting for the rest, but it can already be wrong
+                same_transmission(&event.data[..len.min(6)], &self.pending[..len.min(6)])
+            };
+            if !matches {
+                return Err(diverged(format!(
+                    "sent [{}] but the capture has tx [{}] next",
+                    to_hex(&self.pending),
+                    to_hex(&event.data)
+                )));
+            }
+            if len < event.data.len() {
+                break;
+            }
+            self.pending.drain(..len);
+            self.events.pop_front();
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let event = match self.events.front_mut() {
+            // Nothing more arrives until the code sends the next frame
+            Some(event) if event.direction == Direction::Tx => return Ok(0),
+            Some(event) => event,
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of capture")),
+        };
+
+        let len = buf.len().min(event.data.len());
+        buf[..len].copy_from_slice(&event.data[..len]);
+        event.data.drain(..len);
+        if event.data.is_empty() {
+            self.events.pop_front();
+        }
+        Ok(len)
+    }
+}
+
+// A replay that stays reachable after a `SerialConnection` has taken it, e.g.
+// to check that it was played to the end. Clones play the same capture.
+#[derive(Clone)]
+pub struct SharedReplay(Rc<RefCell<ReplayTransport>>);
+
+impl SharedReplay {
+    pub fn is_finished(&self) -> bool {
+        self.0.borrow().is_finished()
+    }
+
+    // A connection playing this replay
+    pub fn connection(&self) -> SerialConnection {
+        SerialConnection::with_transport(Box::new(self.clone()))
+    }
+}
+
+impl Transport for SharedReplay {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.borrow_mut().write(data)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+fn same_transmission(recorded: &[u8], sent: &[u8]) -> bool {
+    let is_update_time = |frame: &[u8]| frame.len() == 11 && frame[1..3] == [0x40, 0x22];
+    if is_update_time(recorded) && is_update_time(sent) {
+        // Header, command, length and network id, skipping time and checksum
+        return recorded[..6] == sent[..6];
+    }
+    recorded == sent
+}
+
+fn diverged(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("replay diverged: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(direction: Direction, data: &[u8]) -> CaptureEvent {
+        CaptureEvent {
+            time: 0,
+            direction,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn serves_received_chunks_after_matching_writes() {
+        let mut replay = ReplayTransport::new(vec![
+            event(Direction::Tx, &[0x02, 0x40, 0x04, 0x00, 0x44]),
+            event(Direction::Rx, &[0x02, 0x40, 0x84]),
+        ]);
+
+        let mut buf = [0u8; 2];
+        assert_eq!(replay.read(&mut buf).unwrap(), 0);
+        replay.write(&[0x02, 0x40, 0x04, 0x00, 0x44]).unwrap();
+        assert_eq!(replay.read(&mut buf).unwrap(), 2);
+        assert_eq!(buf, [0x02, 0x40]);
+        assert_eq!(replay.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 0x84);
+        assert!(replay.is_finished());
+        assert_eq!(replay.read(&mut buf).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn fails_when_the_writes_diverge() {
+        let mut replay = ReplayTransport::new(vec![event(Direction::Tx, &[0x02, 0x40, 0x04, 0x00, 0x44])]);
+        let err = replay.write(&[0x02, 0x40, 0x00, 0x01, 0x41]).unwrap_err();
+        assert!(err.to_string().starts_with("replay diverged"), "{}", err);
+    }
+
+    #[test]
+    fn collects_split_writes() {
+        let mut replay = ReplayTransport::new(vec![
+            event(Direction::Tx, &[0x02, 0x40, 0x04, 0x00, 0x44]),
+            event(Direction::Tx, &[0x02, 0x40, 0x00, 0x00, 0x40]),
+        ]);
+        replay.write(&[0x02, 0x40]).unwrap();
+        replay.write(&[0x04, 0x00, 0x44, 0x02]).unwrap();
+        assert!(!replay.is_finished());
+        assert!(replay.write(&[0x41]).is_err());
+    }
+
+    #[test]
+    fn ignores_the_time_in_update_time_requests() {
+        let recorded = [0x02, 0x40, 0x22, 0x06, 0x12, 0x34, 0x01, 0x02, 0x03, 0x04, 0x55];
+        let mut replay = ReplayTransport::new(vec![event(Direction::Tx, &recorded)]);
+        replay.write(&[0x02, 0x40, 0x22, 0x06, 0x12, 0x34, 0x0a, 0x0b, 0x0c, 0x0d, 0x66]).unwrap();
+        assert!(replay.is_finished());
+    }
+}
@@ -0,0 +1,190 @@
+use std::{fmt, time::Duration};
+
+use hacklet::dongle::{Dongle, DongleError};
+
+use crate::registry::{Registry, Socket};
+
+// Batch scripts - one command per line, run in a single dongle session:
+//
+//     # evening
+//     on office/0
+//     off 0x1234/1
+//     read lab/1
+//     sleep 5s
+//
+// Sockets are resolved through the registry, so names and literal network ids
+// both work. Sleep durations take an `ms`, `s` or `m` suffix (seconds if none).
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Switch(Socket, bool),
+    Read(Socket),
+    Sleep(Duration),
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Switch(socket, true) => write!(f, "on {}", socket),
+            Step::Switch(socket, false) => write!(f, "off {}", socket),
+            Step::Read(socket) => write!(f, "read {}", socket),
+            Step::Sleep(duration) => write!(f, "sleep {:?}", duration),
+        }
+    }
+}
+
+// Parses a single line, blank lines and comments yield `None`
+pub fn parse_step(registry: &Registry, line: &str) -> Result<Option<Step>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let socket = |reference: &str| {
+        registry
+            .socket(reference)
+            .ok_or_else(|| format!("unknown socket '{}', expected <network>/<socket>", reference))
+    };
+
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let step = match fields.as_slice() {
+        ["on", reference] => Step::Switch(socket(reference)?, true),
+        ["off", reference] => Step::Switch(socket(reference)?, false),
+        ["read", reference] => Step::Read(socket(reference)?),
+        ["sleep", duration] => {
+            Step::Sleep(parse_duration(duration).ok_or_else(|| format!("invalid duration '{}'", duration))?)
+        }
+        _ => return Err(format!("unrecognised command '{}'", line)),
+    };
+    Ok(Some(step))
+}
+
+pub fn parse_script(registry: &Registry, script: &str) -> Result<Vec<Step>, String> {
+    let mut steps = Vec::new();
+    for (index, line) in script.lines().enumerate() {
+        if let Some(step) = parse_step(registry, line).map_err(|err| format!("line {}: {}", index + 1, err))? {
+            steps.push(step);
+        }
+    }
+    Ok(steps)
+}
+
+fn parse_duration(duration: &str) -> Option<Duration> {
+    if let Some(millis) = duration.strip_suffix("ms") {
+        return millis.parse().ok().map(Duration::from_millis);
+    }
+    if let Some(minutes) = duration.strip_suffix('m') {
+        return minutes.parse::<u64>().ok().and_then(|minutes| minutes.checked_mul(60)).map(Duration::from_secs);
+    }
+    let seconds = duration.strip_suffix('s').unwrap_or(duration);
+    // Rejects negative, NaN and too large values, which `from_secs_f64` panics on
+    seconds.parse::<f64>().ok().and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+}
+
+// Runs the steps in order, printing the outcome of each. Failed steps don't
+// stop the script, an interrupt or losing the dongle without getting it back
+// does. Returns the number of steps that failed.
+pub fn run(dongle: &mut Dongle, steps: &[Step]) -> Result<usize, DongleError> {
+    dongle.lock_network()?;
+    let mut failures = 0;
+    for step in steps {
+        if dongle.is_interrupted() {
+            return Err(DongleError::Interrupted);
+        }
+        if !run_step(dongle, step)? {
+            failures += 1;
+        }
+    }
+    Ok(failures)
+}
+
+// Runs a single step and prints its outcome, returns false if it failed
+pub fn run_step(dongle: &mut Dongle, step: &Step) -> Result<bool, DongleError> {
+    let outcome = match step {
+        // Both are safe to repeat, so a step the dongle was lost in the middle
+        // of runs again once it is back
+        Step::Switch(socket, state) => dongle
+            .retrying(|dongle| {
+                dongle.ensure_network(socket.network_id)?;
+                dongle.switch(socket.network_id, socket.socket_id, *state)
+            })
+            .map(|()| "ok".to_string()),
+        Step::Read(socket) => dongle
+            .retrying(|dongle| {
+                dongle.ensure_network(socket.network_id)?;
+                dongle.request_samples(socket.network_id, socket.socket_id)
+            })
+            .map(|samples| {
+                format!("{} sample(s), {} remaining", samples.sample_count, samples.stored_sample_count)
+            }),
+        Step::Sleep(duration) => {
+            dongle.wait(*duration)?;
+            return Ok(true);
+        }
+    };
+
+    match outcome {
+        Ok(outcome) => {
+            println!("{:<24} {}", step.to_string(), outcome);
+            Ok(true)
+        }
+        Err(err @ DongleError::Interrupted) => Err(err),
+        Err(err) if err.is_connection_lost() => Err(err),
+        Err(err) => {
+            println!("{:<24} failed: {}", step.to_string(), err);
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> Registry {
+        let mut registry = Registry::load(std::path::Path::new("/nonexistent/hacklet-registry")).unwrap();
+        registry.add_network("office", 0x1234);
+        registry
+    }
+
+    #[test]
+    fn parses_a_script() {
+        let steps = parse_script(&registry(), "# evening\non office/0\n\noff 0xbeef/1\nread office/1\nsleep 250ms\n").unwrap();
+        assert_eq!(
+            steps,
+            vec![
+                Step::Switch(Socket { network_id: 0x1234, socket_id: 0 }, true),
+                Step::Switch(Socket { network_id: 0xbeef, socket_id: 1 }, false),
+                Step::Read(Socket { network_id: 0x1234, socket_id: 1 }),
+                Step::Sleep(Duration::from_millis(250)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("5s"), Some(Duration::from_secs(5)));
+        assert_eq!(parse_duration("2"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_duration("1m"), Some(Duration::from_secs(60)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("soon"), None);
+    }
+
+    #[test]
+    fn rejects_durations_out_of_range() {
+        assert_eq!(parse_duration("-1s"), None);
+        assert_eq!(parse_duration("inf"), None);
+        assert_eq!(parse_duration("NaN"), None);
+        assert_eq!(parse_duration("1e30"), None);
+        assert_eq!(parse_duration("18446744073709551615m"), None);
+    }
+
+    #[test]
+    fn reports_the_failing_line() {
+        let err = parse_script(&registry(), "on office/0\nflip office/1\n").unwrap_err();
+        assert!(err.starts_with("line 2:"), "{}", err);
+
+        let err = parse_script(&registry(), "on garage/0\n").unwrap_err();
+        assert!(err.contains("garage/0"), "{}", err);
+    }
+}
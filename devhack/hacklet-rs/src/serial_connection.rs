@@ -1,82 +1,123 @@
-use log::{debug, info};
-use libftdi1_sys::*;
+use log::{debug, info, warn};
 use std::{
+    io,
+    path::Path,
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use crate::messages::requests::BootRequest;
+use crate::capture::{CaptureWriter, Direction};
+#[cfg(feature = "ftdi")]
+use crate::ftdi::FtdiTransport;
+use crate::transport::Transport;
 
-pub const SIO_DISABLE_FLOW_CTRL: u32 = 0;
+#[cfg(feature = "ftdi")]
+pub(crate) const SIO_DISABLE_FLOW_CTRL: u32 = 0;
 
-#[derive(Debug)]
 pub struct SerialConnection {
-    context: *mut libftdi1_sys::ftdi_context,
+    transport: Box<dyn Transport>,
     receive_buffer: Vec<u8>,
+    capture: Option<CaptureWriter>,
 }
 
 impl SerialConnection {
-    pub fn new() -> Self
+    // Opens the first Hacklet dongle found on USB
+    #[cfg(feature = "ftdi")]
+    pub fn new() -> io::Result<Self>
     {
-        let context = unsafe { ftdi_new() };
-
-        unsafe {
-            if ftdi_usb_open(context, 0x0403, 0x8c81) < 0 {
-                panic!("Could not open FTDI device");
-            }
-
-            // Set bitmode and baudrate
-            ftdi_set_bitmode(context, 0x00, ftdi_mpsse_mode::BITMODE_RESET.0 as u8);
-            ftdi_set_baudrate(context, 115200);
-            ftdi_setflowctrl(context, SIO_DISABLE_FLOW_CTRL as i32);
-            ftdi_setdtr(context, 1);
-            ftdi_setrts(context, 1);
-        }
+        Ok(SerialConnection::with_transport(Box::new(FtdiTransport::open()?)))
+    }
 
+    pub fn with_transport(transport: Box<dyn Transport>) -> Self
+    {
         SerialConnection
         {
-            context,
+            transport,
             receive_buffer: vec![],
+            capture: None,
         }
+    }
 
+    // Records every chunk sent and received from now on to a capture file
+    pub fn record_to(&mut self, path: &Path) -> io::Result<()>
+    {
+        self.capture = Some(CaptureWriter::create(path)?);
+        info!("Recording to {}", path.display());
+        Ok(())
     }
 
-    pub fn close(&mut self) 
+    // Takes over the capture `lost` was recording to, when this connection
+    // replaces it
+    pub(crate) fn keep_recording(&mut self, lost: &mut SerialConnection)
     {
-        unsafe {
-            ftdi_usb_close(self.context);
-            ftdi_free(self.context);
-        }
-        info!("Closed FTDI device connection");
+        self.capture = lost.capture.take();
     }
 
-    pub fn transmit(&mut self, command: &[u8]) 
+    // Fails when the dongle can no longer be reached, e.g. it was unplugged
+    pub fn transmit(&mut self, command: &[u8]) -> io::Result<()>
     {
         debug!("TX: {:?}", command);
-        unsafe {
-            if ftdi_write_data(self.context, command.as_ptr(), command.len() as i32) < 0 {
-                panic!("Failed to write data");
+        self.record(Direction::Tx, command);
+        self.transport.write(command)
+    }
+
+    pub fn receive(&mut self, bytes: usize) -> io::Result<Vec<u8>>
+    {
+        loop {
+            if self.receive_buffer.len() >= bytes {
+                let response: Vec<u8> = self.receive_buffer.drain(..bytes).collect();
+                debug!("RX: {:?}", response);
+                return Ok(response);
+            }
+
+            if !self.fill_buffer()? {
+                sleep(Duration::from_millis(100));
             }
         }
     }
 
-    pub fn receive(&mut self, bytes: usize) -> Vec<u8>
+    // Like receive, but gives up once the timeout has elapsed
+    pub fn receive_timeout(&mut self, bytes: usize, timeout: Duration) -> io::Result<Option<Vec<u8>>>
     {
+        let start_time = Instant::now();
         loop {
             if self.receive_buffer.len() >= bytes {
                 let response: Vec<u8> = self.receive_buffer.drain(..bytes).collect();
                 debug!("RX: {:?}", response);
-                return response;
+                return Ok(Some(response));
             }
 
-            let mut buf = [0u8; 64]; // Buffer for reading data
-            unsafe {
-                let chunk = ftdi_read_data(self.context, buf.as_mut_ptr(), buf.len() as i32);
-                if chunk > 0 {
-                    self.receive_buffer.extend_from_slice(&buf[..chunk as usize]);
-                } else {
-                    sleep(Duration::from_millis(100));
-                }
+            let elapsed = start_time.elapsed();
+            if elapsed >= timeout {
+                return Ok(None);
+            }
+
+            if !self.fill_buffer()? {
+                sleep((timeout - elapsed).min(Duration::from_millis(100)));
+            }
+        }
+    }
+
+    // Reads whatever the device has available, returns false if nothing arrived
+    fn fill_buffer(&mut self) -> io::Result<bool>
+    {
+        let mut buf = [0u8; 64]; // Buffer for reading data
+        match self.transport.read(&mut buf)? {
+            0 => Ok(false),
+            chunk => {
+                self.receive_buffer.extend_from_slice(&buf[..chunk]);
+                self.record(Direction::Rx, &buf[..chunk]);
+                Ok(true)
+            }
+        }
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8])
+    {
+        if let Some(capture) = &mut self.capture {
+            if let Err(err) = capture.record(direction, data) {
+                warn!("Stopped recording: {}", err);
+                self.capture = None;
             }
         }
     }
@@ -97,70 +138,30 @@ pub enum BitbangMode {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use mockall::predicate::*;
-    use mockall::{mock};
-    use libftdi1_sys;
-
-    mock! {
-        pub FtdiContext {}
-        impl FtdiContext for FtdiContext {
-            fn usb_open(&self, vendor_id: u16, product_id: u16);
-            fn set_bitmode(&self, bitmask: u8, mode: BitbangMode);
-            fn set_baudrate(&self, baudrate: u32);
-            fn set_flowctrl(&self, flowctrl: u32);
-            fn set_dtr(&self, value: u8);
-            fn set_rts(&self, value: u8);
-            fn write_data(&self, data: &[u8]);
-            fn read_data(&self) -> Vec<u8>;
-        }
-    }
+    use crate::messages::requests::BootRequest;
+    use crate::replay::ReplayTransport;
 
-    #[test]
-    fn initialization_creates_serial_port_with_no_flow_control() {
-        let mut ftdi = MockFtdiContext::new();
-        ftdi.expect_usb_open()
-            .with(eq(0x0403), eq(0x8c81))
-            .times(1);
-        ftdi.expect_set_bitmode()
-            .with(eq(0x00), eq(BitbangMode::Reset))
-            .times(1);
-        ftdi.expect_set_baudrate()
-            .with(eq(115200))
-            .times(1);
-        ftdi.expect_set_flowctrl()
-            .with(eq(SIO_DISABLE_FLOW_CTRL))
-            .times(1);
-        ftdi.expect_set_dtr()
-            .with(eq(1))
-            .times(1);
-        ftdi.expect_set_rts()
-            .with(eq(1))
-            .times(1);
-
-        SerialConnection::new(Box::new(ftdi));
+    fn connection(frames: Vec<(Direction, Vec<u8>)>) -> SerialConnection {
+        ReplayTransport::from_frames(frames).into_shared().connection()
     }
 
     #[test]
     fn transmitting_is_successful() {
-
-        let mut ftdi = MockFtdiContext::new();
-        ftdi.expect_write_data()
-            .with(eq(vec![0x02, 0x40, 0x04, 0x00, 0x44]))
-            .times(1);
-
-        let mut connection = SerialConnection::new(Box::new(ftdi));
-        connection.transmit(&BootRequest::new().as_bytes());
+        let mut connection = connection(vec![(Direction::Tx, vec![0x02, 0x40, 0x04, 0x00, 0x44])]);
+        connection.transmit(&BootRequest::new().as_bytes()).unwrap();
     }
 
     #[test]
     fn receiving_is_successful() {
+        let mut connection = connection(vec![(Direction::Rx, vec![0x02, 0x40, 0x84])]);
+        assert_eq!(connection.receive(1).unwrap(), vec![0x02]);
+        assert_eq!(connection.receive(2).unwrap(), vec![0x40, 0x84]);
+    }
 
-        let mut ftdi = MockFtdiContext::new();
-        ftdi.expect_read_data()
-            .returning(|| vec![0x02]);
-
-        let mut connection = SerialConnection::new(Box::new(ftdi));
-        let data = connection.receive(1);
-        assert_eq!(data, vec![0x02]);
+    #[test]
+    fn receive_timeout_gives_up_when_nothing_arrives() {
+        let mut connection = connection(vec![(Direction::Tx, vec![0x02, 0x40, 0x04, 0x00, 0x44])]);
+        let received = connection.receive_timeout(1, Duration::from_millis(10)).unwrap();
+        assert_eq!(received, None);
     }
 }
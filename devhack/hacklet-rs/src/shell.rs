@@ -0,0 +1,333 @@
+use std::{
+    ops::{Deref, DerefMut},
+    path::PathBuf,
+    sync::atomic::Ordering,
+    time::Duration,
+};
+
+use rustyline::{
+    completion::Completer,
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    history::DefaultHistory,
+    validate::Validator,
+    Context, Editor, Helper,
+};
+
+use hacklet::{
+    dongle::Dongle,
+    messages::{frame::Frame, parse_hex, to_hex, Message},
+};
+
+use crate::{
+    registry::Registry,
+    script::{self, Step},
+};
+
+// Interactive shell - boots the dongle once and then takes commands until
+// `exit` or Ctrl-D:
+//
+//     on <socket>, off <socket>, read <socket>, sleep <duration>
+//     on <group>, off <group>, read <group>   each socket of a group in turn
+//     select <network>   handshake with a network
+//     lock, unlock       lock or unlock the network for pairing
+//     raw <command> <payload>   send a frame built like `hacklet raw` and
+//                        print the frames that come back
+
+const COMMANDS: &[&str] = &["on", "off", "read", "sleep", "select", "lock", "unlock", "raw", "help", "exit"];
+
+// How long `raw` waits for frames to come back
+const RAW_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct ShellHelper {
+    names: Vec<String>,
+}
+
+impl ShellHelper {
+    fn new(registry: &Registry) -> Self {
+        let mut names: Vec<String> = registry
+            .networks()
+            .iter()
+            .map(|network| format!("{}/", network.name))
+            .collect();
+        names.extend(registry.groups().iter().map(|group| group.name.clone()));
+        names.sort();
+        names.dedup();
+        ShellHelper { names }
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        let word = &line[start..];
+
+        let candidates = if start == 0 {
+            COMMANDS.iter().map(|command| command.to_string()).filter(|c| c.starts_with(word)).collect()
+        } else {
+            self.names.iter().filter(|name| name.starts_with(word)).cloned().collect()
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+fn history_path() -> PathBuf {
+    Registry::default_path().with_file_name("history")
+}
+
+// The dongle for the length of a shell session. `unlock` has to outlive the
+// command, so the network's guard is disarmed and this relocks instead when
+// the shell is left, whether by `exit`, an error or a panic.
+struct Session<'a> {
+    dongle: &'a mut Dongle,
+    unlocked: bool,
+}
+
+impl Deref for Session<'_> {
+    type Target = Dongle;
+
+    fn deref(&self) -> &Dongle {
+        self.dongle
+    }
+}
+
+impl DerefMut for Session<'_> {
+    fn deref_mut(&mut self) -> &mut Dongle {
+        self.dongle
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        // Never leave the network open for pairing behind us
+        if self.unlocked {
+            if let Err(err) = self.dongle.lock_network() {
+                eprintln!("Could not lock the network: {}", err);
+            }
+        }
+    }
+}
+
+pub fn run(dongle: &mut Dongle, registry: &Registry) {
+    let mut editor: Editor<ShellHelper, DefaultHistory> = match Editor::new() {
+        Ok(editor) => editor,
+        Err(err) => {
+            eprintln!("Could not start shell: {}", err);
+            return;
+        }
+    };
+    editor.set_helper(Some(ShellHelper::new(registry)));
+    let _ = editor.load_history(&history_path());
+
+    let interrupted = dongle.interrupt_flag();
+    let mut dongle = Session { dongle, unlocked: false };
+
+    loop {
+        let line = match editor.readline("hacklet> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("{}", err);
+                break;
+            }
+        };
+        let _ = editor.add_history_entry(line.as_str());
+        interrupted.store(false, Ordering::SeqCst);
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let result = match fields.as_slice() {
+            [] => Ok(()),
+            ["exit"] | ["quit"] => break,
+            ["help"] => {
+                println!("commands: {}", COMMANDS.join(", "));
+                Ok(())
+            }
+            ["select", network] => match registry.network(network) {
+                Some(network_id) => dongle
+                    .select_network(network_id)
+                    .map(|()| println!("selected network 0x{:04x}", network_id)),
+                None => {
+                    println!("unknown network '{}'", network);
+                    Ok(())
+                }
+            },
+            ["lock"] => dongle.lock_network().map(|()| dongle.unlocked = false),
+            ["unlock"] => dongle
+                .unlock_network()
+                .map(|network| network.keep_unlocked())
+                .map(|()| dongle.unlocked = true),
+            ["raw", command, payload @ ..] => match raw_frame(command, payload) {
+                Ok(frame) => {
+                    let bytes = frame.as_bytes();
+                    print_frame("TX", &bytes);
+                    dongle.raw(&bytes, RAW_TIMEOUT).map(|frames| {
+                        for frame in frames {
+                            print_frame("RX", &frame);
+                        }
+                    })
+                }
+                Err(err) => {
+                    println!("{}", err);
+                    Ok(())
+                }
+            },
+            ["raw"] => {
+                println!("usage: raw <command> <payload>");
+                Ok(())
+            }
+            [command @ ("on" | "off" | "read"), name] if registry.group(name).is_some() => {
+                match registry.sockets(name) {
+                    Some(sockets) => sockets.into_iter().try_for_each(|socket| {
+                        let step = match *command {
+                            "on" => Step::Switch(socket, true),
+                            "off" => Step::Switch(socket, false),
+                            _ => Step::Read(socket),
+                        };
+                        script::run_step(&mut dongle, &step).map(|_| ())
+                    }),
+                    None => {
+                        println!("group '{}' refers to an unknown socket", name);
+                        Ok(())
+                    }
+                }
+            }
+            _ => match script::parse_step(registry, &line) {
+                Ok(Some(step)) => script::run_step(&mut dongle, &step).map(|_| ()),
+                Ok(None) => Ok(()),
+                Err(err) => {
+                    println!("{}", err);
+                    Ok(())
+                }
+            },
+        };
+
+        // Requests that merely failed have been reported already. A lost
+        // dongle is opened again, the command that lost it is not repeated.
+        match result {
+            Err(err) if err.is_connection_lost() => {
+                eprintln!("Lost the dongle: {}", err);
+                dongle.unlocked = false;
+                if !dongle.can_reconnect() {
+                    break;
+                }
+                match dongle.reconnect() {
+                    Ok(info) => println!("reconnected to dongle {}", info),
+                    Err(err) => {
+                        eprintln!("Could not reconnect: {}", err);
+                        break;
+                    }
+                }
+            }
+            Err(err) => println!("{}", err),
+            Ok(()) => {}
+        }
+    }
+
+    if let Some(parent) = history_path().parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    let _ = editor.save_history(&history_path());
+}
+
+// Builds the frame `raw` sends from a command code and hex payload words, the
+// header, length and checksum are filled in
+pub fn raw_frame(command: &str, payload: &[&str]) -> Result<Frame, String> {
+    let command = match parse_hex(command).as_deref() {
+        Some(&[high, low]) => u16::from_be_bytes([high, low]),
+        _ => return Err(format!("Invalid command code '{}', expected two bytes of hex", command)),
+    };
+
+    let words = payload.join(" ");
+    let payload = parse_hex(&words).ok_or_else(|| format!("Invalid payload '{}'", words))?;
+    if payload.len() > u8::MAX as usize {
+        return Err(format!("Payload is {} bytes, frames carry at most {}", payload.len(), u8::MAX));
+    }
+    Ok(Frame::new(command, payload))
+}
+
+// Prints the raw bytes followed by the decoded frame
+pub fn print_frame(direction: &str, bytes: &[u8]) {
+    match Frame::read(bytes) {
+        Ok((_, frame)) => println!("{} {}  ({})", direction, to_hex(bytes), frame),
+        Err(_) => println!("{} {}  (not a frame)", direction, to_hex(bytes)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+
+    use hacklet::{
+        capture::Direction,
+        messages::{requests::*, responses::*},
+        replay::ReplayTransport,
+    };
+
+    fn complete(helper: &ShellHelper, line: &str) -> (usize, Vec<String>) {
+        let history = DefaultHistory::new();
+        helper.complete(line, line.len(), &Context::new(&history)).unwrap()
+    }
+
+    #[test]
+    fn completes_commands_and_device_names() {
+        let contents = "network office 0x1234\nnetwork lab 0xbeef\ngroup desk office/0 office/1\n";
+        let registry = Registry::parse(std::path::Path::new("registry"), contents).unwrap();
+        let helper = ShellHelper::new(&registry);
+
+        assert_eq!(complete(&helper, "un"), (0, vec!["unlock".to_string()]));
+        assert_eq!(complete(&helper, "on of"), (3, vec!["office/".to_string()]));
+        assert_eq!(complete(&helper, "on d"), (3, vec!["desk".to_string()]));
+        assert_eq!(
+            complete(&helper, "read "),
+            (5, vec!["desk".to_string(), "lab/".to_string(), "office/".to_string()])
+        );
+    }
+
+    #[test]
+    fn builds_raw_frames_with_their_checksum() {
+        assert_eq!(raw_frame("4004", &[]).unwrap().as_bytes(), BootRequest::new().as_bytes());
+        assert_eq!(
+            raw_frame("0x4003", &["1234", "05 00"]).unwrap().as_bytes(),
+            HandshakeRequest::new(0x1234).as_bytes()
+        );
+        assert!(raw_frame("40", &[]).is_err());
+        assert!(raw_frame("4004", &["1"]).is_err());
+    }
+
+    #[test]
+    fn relocks_the_network_when_a_command_panics() {
+        let events = vec![
+            (Direction::Tx, UnlockRequest::new().as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+            (Direction::Tx, LockRequest::new().as_bytes()),
+            (Direction::Rx, LockResponse::new().as_bytes()),
+        ];
+        let transport = ReplayTransport::from_frames(events).into_shared();
+        let mut dongle = Dongle::new(transport.connection());
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let mut session = Session { dongle: &mut dongle, unlocked: false };
+            session.unlock_network().unwrap().keep_unlocked();
+            session.unlocked = true;
+            panic!("command failed");
+        }));
+        assert!(result.is_err());
+        assert!(transport.is_finished());
+    }
+}
@@ -0,0 +1,73 @@
+use log::info;
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use crate::transport::Transport;
+
+// How long a read waits for bytes before reporting that nothing has arrived
+const READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+// A dongle on another machine, reached through `hacklet bridge`. The stream
+// carries the raw serial bytes in both directions, there is no framing or
+// option negotiation on top.
+#[derive(Debug)]
+pub struct TcpTransport
+{
+    stream: TcpStream,
+}
+
+impl TcpTransport
+{
+    pub fn connect<A: ToSocketAddrs>(address: A) -> io::Result<Self>
+    {
+        let stream = TcpStream::connect(address)?;
+        TcpTransport::from_stream(stream)
+    }
+
+    pub fn from_stream(stream: TcpStream) -> io::Result<Self>
+    {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        info!("Connected to bridge at {}", stream.peer_addr()?);
+        Ok(TcpTransport { stream })
+    }
+}
+
+impl Transport for TcpTransport
+{
+    fn write(&mut self, data: &[u8]) -> io::Result<()>
+    {
+        self.stream.write_all(data)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        match self.stream.read(buf) {
+            Ok(0) => Err(io::Error::new(ErrorKind::ConnectionAborted, "bridge closed the connection")),
+            Ok(read) => Ok(read),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// Accepts `tcp://host:port`, the only remote device kind so far
+pub fn parse_device(device: &str) -> Option<&str>
+{
+    device.strip_prefix("tcp://").filter(|address| !address.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tcp_devices() {
+        assert_eq!(parse_device("tcp://closet:7070"), Some("closet:7070"));
+        assert_eq!(parse_device("tcp://"), None);
+        assert_eq!(parse_device("/dev/ttyUSB0"), None);
+    }
+}
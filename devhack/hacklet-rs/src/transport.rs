@@ -0,0 +1,13 @@
+use std::io;
+
+// The byte pipe to the dongle. Implementations only move raw bytes around,
+// `SerialConnection` does the buffering and recording on top of them.
+pub trait Transport
+{
+    // Writes all of `data`
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+
+    // Reads whatever is available into `buf` without blocking for long,
+    // returning Ok(0) when nothing has arrived
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
@@ -1,4 +1,4 @@
-const VERSION: &str = "0.1.0";
+pub const VERSION: &str = "0.1.0";
 
 #[cfg(test)]
 mod tests {